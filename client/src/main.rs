@@ -1,15 +1,25 @@
 #![allow(unused)]
-mod message;
-
-use message::Message;
 use rustyline::Editor;
+use shared::{
+    command::ClientCommand,
+    message::{Batch, Command, Message, ToIrc},
+};
 use std::{
     env,
     io::{self, Error, ErrorKind, Read, Write},
     net::TcpStream,
-    process, str, thread,
+    process, str,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+/// How long someone is shown as typing after their last `active` typing notification.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
 // fn main() {
 //     // let m = Message::from(":arvind!arvind@localhost JOIN #foo").unwrap();
 //     // println!("{m:?}");
@@ -57,16 +67,30 @@ fn main() {
     });
     let mut writer = reader.try_clone().expect("Failed to clone stream.");
 
+    // Ask the server to echo our messages back so they're shown the way everyone else sees them,
+    // for tags so we hear about who's typing, and for multi-line messages so pasted text arrives
+    // in one piece. They're requested separately since a server without multi-line support would
+    // refuse both.
+    writer
+        .write_all(
+            b"CAP REQ :echo-message message-tags\r\nCAP REQ :batch draft/multiline\r\nCAP END\r\n",
+        )
+        .expect("Failed to send message to the server.");
+
+    // Whether the server accepted multi-line messages
+    let multiline = Arc::new(AtomicBool::new(false));
+
     // Create send and receive threads
-    let send_thread = thread::spawn(move || send_handler(writer));
-    let recv_thread = thread::spawn(move || recv_handler(reader));
+    let send_multiline = multiline.clone();
+    let send_thread = thread::spawn(move || send_handler(writer, send_multiline));
+    let recv_thread = thread::spawn(move || recv_handler(reader, multiline));
 
     // Wait for both threads to terminate
     send_thread.join();
     recv_thread.join();
 }
 
-fn send_handler(mut writer: TcpStream) {
+fn send_handler(mut writer: TcpStream, multiline: Arc<AtomicBool>) {
     let mut editor = Editor::<()>::new();
 
     loop {
@@ -95,9 +119,20 @@ fn send_handler(mut writer: TcpStream) {
         // Build message from input
         // let msg = message_from_input(message.trim_end());
 
-        // Send message to server
+        // Send message to server, ending it with a line break so the server knows where it stops.
+        // Pasted text can span several lines, which are sent together. The editor uses bracketed
+        // paste, so a paste comes back as one input with its line breaks in it, unless the
+        // terminal doesn't support it and each line is entered on its own.
+        let text = if message.contains('\n') {
+            messages_from_paste(&message, multiline.load(Ordering::Relaxed))
+                .iter()
+                .map(ToIrc::to_irc)
+                .collect()
+        } else {
+            format!("{}\r\n", message)
+        };
         writer
-            .write_all(message.as_bytes())
+            .write_all(text.as_bytes())
             .expect("Failed to send message to the server.");
 
         // Exit if user wishes to
@@ -107,18 +142,29 @@ fn send_handler(mut writer: TcpStream) {
     }
 }
 
-fn recv_handler(mut reader: TcpStream) {
+fn recv_handler(mut reader: TcpStream, multiline: Arc<AtomicBool>) {
+    // Nickname of whoever is typing a message to us, shown in the input area, and when they were
+    // last said to be typing
+    let mut typing: Option<(String, Instant)> = None;
+
+    // Stop waiting on the server every so often, so that typing notifications can expire
+    reader
+        .set_read_timeout(Some(Duration::from_secs(1)))
+        .expect("Failed to set the read timeout.");
+
     loop {
         // Read response from server
         let mut response = vec![0; shared::MESSAGE_SIZE];
-        match reader.read(&mut response) {
+        let received = match reader.read(&mut response) {
             Ok(bytes) => {
                 if bytes == 0 {
                     print!("\r");
                     io::stdout().flush().expect("Failed to flush stdout.");
                     break;
                 }
+                true
             }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => false,
             Err(err) => panic!("{err}"),
         };
 
@@ -129,13 +175,139 @@ fn recv_handler(mut reader: TcpStream) {
             .replace('\0', "");
         let response_str = response_str.trim_end();
 
+        // Remember whether the server acknowledged multi-line messages
+        let acknowledged = response_str
+            .lines()
+            .filter_map(|line| Message::from(line).ok())
+            .filter(|message| matches!(message.command, Command::Cap))
+            .filter(|message| message.params.get(1).map(String::as_str) == Some("ACK"))
+            .any(|message| {
+                message.params[2..]
+                    .iter()
+                    .flat_map(|caps| caps.split_whitespace())
+                    .any(|cap| cap == "draft/multiline")
+            });
+        if acknowledged {
+            multiline.store(true, Ordering::Relaxed);
+        }
+
+        // Typing notifications update the input area instead of being printed like other messages
+        let was_typing = typing.as_ref().map(|(nickname, _)| nickname.clone());
+        let mut lines = vec![];
+        for line in response_str.lines() {
+            let message = Message::from(line).ok();
+            let sender = message
+                .as_ref()
+                .and_then(|message| message.prefix.as_deref()?.split('!').next());
+            let is_typing = |nickname| {
+                typing
+                    .as_ref()
+                    .is_some_and(|(typing, _)| typing == nickname)
+            };
+            let notification = message
+                .as_ref()
+                .zip(sender)
+                .and_then(|(message, nickname)| {
+                    let state = message.tags.get("+typing")?;
+                    matches!(message.command, Command::TagMsg).then_some((nickname, state))
+                });
+            match notification {
+                Some((nickname, state)) if state == "active" => {
+                    typing = Some((nickname.to_string(), Instant::now()))
+                }
+                Some((nickname, _)) => {
+                    if is_typing(nickname) {
+                        typing = None;
+                    }
+                }
+                None => {
+                    // Whoever was typing is done once their message arrives
+                    let is_text = message.as_ref().is_some_and(|message| {
+                        matches!(message.command, Command::PrivMsg | Command::Notice)
+                    });
+                    if is_text && sender.is_some_and(is_typing) {
+                        typing = None;
+                    }
+                    lines.push(line);
+                }
+            }
+        }
+
+        // Clients keep sending `active` while their user types, so one that stops coming means
+        // they stopped without saying so
+        if typing
+            .as_ref()
+            .is_some_and(|(_, since)| since.elapsed() >= TYPING_TIMEOUT)
+        {
+            typing = None;
+        }
+
+        // Only redraw the input area when there's something new, since it clears what's being typed
+        let is_changed = typing.as_ref().map(|(nickname, _)| nickname) != was_typing.as_ref();
+        if !received && !is_changed {
+            continue;
+        }
+
         print!("\r"); // Clear the current line; TODO: this needs some work
-        println!("<Server> {:?}", response_str);
-        print!("> ");
+        if !lines.is_empty() {
+            println!("<Server> {:?}", lines.join("\r\n"));
+        }
+        match &typing {
+            Some((nickname, _)) => print!("({} is typing…) > ", nickname),
+            None => print!("> "),
+        }
         io::stdout().flush().expect("Failed to flush stdout.");
     }
 }
 
+/// Turn pasted input spanning several lines, e.g. `PRIVMSG #rust :first` followed by `second`, into
+/// the messages to send. The text of a PRIVMSG or NOTICE goes out as one `draft/multiline` batch if
+/// the server supports it, and as a message per line otherwise. Anything else is sent line by line
+/// as it was typed.
+fn messages_from_paste(input: &str, multiline: bool) -> Vec<Message> {
+    let line_by_line = || {
+        input
+            .lines()
+            .filter_map(|line| Message::from(line).ok())
+            .collect()
+    };
+    let mut lines = input.lines();
+    let first = match Message::from(lines.next().unwrap_or_default()) {
+        Ok(message) => message,
+        Err(_) => return line_by_line(),
+    };
+    // A batch has a single target
+    let (target, is_notice) = match ClientCommand::try_from(&first) {
+        Ok(ClientCommand::PrivMsg { mut targets, .. }) if targets.len() == 1 => {
+            (targets.remove(0), false)
+        }
+        Ok(ClientCommand::Notice { mut targets, .. }) if targets.len() == 1 => {
+            (targets.remove(0), true)
+        }
+        _ => return line_by_line(),
+    };
+
+    let mut messages = vec![first];
+    messages.extend(lines.map(|line| -> Message {
+        let targets = vec![target.clone()];
+        let text = line.to_string();
+        let command = if is_notice {
+            ClientCommand::Notice { targets, text }
+        } else {
+            ClientCommand::PrivMsg { targets, text }
+        };
+        command.into()
+    }));
+
+    if multiline {
+        Batch::new("draft/multiline", &[&target]).wrap(None, messages)
+    } else {
+        // Empty lines can't be sent on their own
+        messages.retain(|message| !message.params[1].is_empty());
+        messages
+    }
+}
+
 // fn message_from_input(input: &str) -> Message {
 //     // Command
 //     if input.starts_with("/") {
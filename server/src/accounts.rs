@@ -0,0 +1,67 @@
+use crate::store;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, io};
+
+/// Registered accounts users can log in to with SASL.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AccountStore {
+    #[serde(skip)]
+    path: String,
+    /// Accounts keyed by their name in lowercase, since names are case-insensitive
+    accounts: BTreeMap<String, Account>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    /// Name of the account as it was registered
+    pub name: String,
+    /// bcrypt hash of the account's password
+    pub password: String,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+impl AccountStore {
+    /// Read the accounts stored at `path`. If the file doesn't exist yet, there are no accounts.
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut accounts: AccountStore = store::load_json(path)?;
+        accounts.path = path.to_string();
+        Ok(accounts)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        store::save_json(&self.path, self)
+    }
+
+    /// Add an account with an already hashed password. Return whether it was added, which it isn't
+    /// if the name is taken.
+    pub fn register(
+        &mut self,
+        name: &str,
+        password: &str,
+        email: Option<String>,
+    ) -> io::Result<bool> {
+        if self.get(name).is_some() {
+            return Ok(false);
+        }
+
+        let account = Account {
+            name: name.to_string(),
+            password: password.to_string(),
+            email,
+        };
+        self.accounts.insert(name.to_lowercase(), account);
+        self.save()?;
+        Ok(true)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Account> {
+        self.accounts.get(&name.to_lowercase())
+    }
+
+    /// Return the account with the given name if the password is correct.
+    pub fn authenticate(&self, name: &str, password: &str) -> Option<&Account> {
+        self.get(name)
+            .filter(|account| bcrypt::verify(password, &account.password).unwrap_or(false))
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{mask, store};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    net::IpAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// K-lines and D-lines set by operators. The list is written to its file after every change, and
+/// expired bans are dropped when it is.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BanList {
+    #[serde(skip)]
+    path: String,
+    /// Bans on `user@host` masks, checked when a user registers
+    klines: Vec<Ban>,
+    /// Bans on IP addresses or CIDR networks, checked when a connection is accepted
+    dlines: Vec<Ban>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub mask: String,
+    pub reason: String,
+    /// Nickname of the operator that set the ban
+    pub set_by: String,
+    /// Unix timestamp of when the ban expires, or `None` if it's permanent
+    pub expires: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BanKind {
+    KLine,
+    DLine,
+}
+
+impl BanList {
+    /// Read the bans stored at `path`. If the file doesn't exist yet, the list starts out empty.
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut bans: BanList = store::load_json(path)?;
+        bans.path = path.to_string();
+        Ok(bans)
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        // Drop expired bans so the file doesn't grow forever
+        let now = now();
+        self.klines.retain(|ban| !ban.is_expired(now));
+        self.dlines.retain(|ban| !ban.is_expired(now));
+
+        store::save_json(&self.path, self)
+    }
+
+    /// Add a ban, replacing any existing ban of the same kind on the same mask.
+    pub fn add(&mut self, kind: BanKind, ban: Ban) -> io::Result<()> {
+        let bans = self.bans_mut(kind);
+        bans.retain(|existing| existing.mask != ban.mask);
+        bans.push(ban);
+        self.save()
+    }
+
+    /// Remove the ban on `mask`. Return whether there was one.
+    pub fn remove(&mut self, kind: BanKind, mask: &str) -> io::Result<bool> {
+        let bans = self.bans_mut(kind);
+        let count = bans.len();
+        bans.retain(|ban| ban.mask != mask);
+        let removed = bans.len() != count;
+        if removed {
+            self.save()?;
+        }
+        Ok(removed)
+    }
+
+    /// Return the bans of the given kind that are still in effect.
+    pub fn list(&self, kind: BanKind) -> impl Iterator<Item = &Ban> {
+        let now = now();
+        let bans = match kind {
+            BanKind::KLine => &self.klines,
+            BanKind::DLine => &self.dlines,
+        };
+        bans.iter().filter(move |ban| !ban.is_expired(now))
+    }
+
+    /// Return the K-line matching a `user@host` string, if there is one.
+    pub fn find_kline(&self, user_host: &str) -> Option<&Ban> {
+        self.list(BanKind::KLine)
+            .find(|ban| mask::matches(&ban.mask, user_host))
+    }
+
+    /// Return the D-line matching an IP address, if there is one.
+    pub fn find_dline(&self, address: IpAddr) -> Option<&Ban> {
+        self.list(BanKind::DLine)
+            .find(|ban| mask::matches_network(&ban.mask, address))
+    }
+
+    fn bans_mut(&mut self, kind: BanKind) -> &mut Vec<Ban> {
+        match kind {
+            BanKind::KLine => &mut self.klines,
+            BanKind::DLine => &mut self.dlines,
+        }
+    }
+}
+
+impl Ban {
+    /// Create a ban lasting `minutes`, or a permanent one if no duration is given.
+    pub fn new(mask: &str, reason: &str, set_by: &str, minutes: Option<u64>) -> Self {
+        Ban {
+            mask: mask.to_string(),
+            reason: reason.to_string(),
+            set_by: set_by.to_string(),
+            expires: minutes.map(|minutes| now() + minutes * 60),
+        }
+    }
+
+    /// Describe who set the ban and when it expires, for STATS replies.
+    pub fn describe(&self) -> String {
+        match self.expires {
+            Some(expires) => format!(
+                "{} (set by {}, expires in {} minutes)",
+                self.reason,
+                self.set_by,
+                expires.saturating_sub(now()).div_ceil(60)
+            ),
+            None => format!("{} (set by {})", self.reason, self.set_by),
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        matches!(self.expires, Some(expires) if expires <= now)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,307 @@
+use crate::{
+    config::Config,
+    error::ServerError,
+    server::{send_to_user, try_register, CommandResponse},
+    state::ServerState,
+};
+use shared::message::{join_list, Command, Message, ReplyCode, Response};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+
+/// Capabilities mapped to their value, if they have one.
+pub type Capabilities = BTreeMap<String, Option<String>>;
+
+/// Return the capabilities the server offers with the given configuration.
+pub fn supported(config: &Config) -> Capabilities {
+    let mut capabilities = Capabilities::new();
+    capabilities.insert("account-notify".to_string(), None);
+    capabilities.insert("account-tag".to_string(), None);
+    capabilities.insert("batch".to_string(), None);
+    capabilities.insert("cap-notify".to_string(), None);
+    capabilities.insert("draft/account-registration".to_string(), None);
+    capabilities.insert("draft/chathistory".to_string(), None);
+    capabilities.insert("draft/message-redaction".to_string(), None);
+    capabilities.insert(
+        "draft/multiline".to_string(),
+        Some(format!(
+            "max-bytes={},max-lines={}",
+            config.multiline_max_bytes, config.multiline_max_lines
+        )),
+    );
+    capabilities.insert("echo-message".to_string(), None);
+    capabilities.insert("extended-join".to_string(), None);
+    capabilities.insert("invite-notify".to_string(), None);
+    capabilities.insert("message-tags".to_string(), None);
+    capabilities.insert("sasl".to_string(), Some("PLAIN".to_string()));
+    capabilities.insert("server-time".to_string(), None);
+
+    capabilities.retain(|name, _| !config.disabled_capabilities.contains(name));
+    capabilities
+}
+
+/// Recompute the capabilities after the configuration changed, and tell users that negotiated
+/// `cap-notify` about the ones that were added or removed.
+pub fn refresh(state: &ServerState) -> Result<(), ServerError> {
+    let new = supported(&state.config.read().unwrap());
+    let old = std::mem::replace(&mut *state.capabilities.write().unwrap(), new.clone());
+
+    let removed = old
+        .keys()
+        .filter(|name| !new.contains_key(*name))
+        .cloned()
+        .collect::<Vec<_>>();
+    let added = new
+        .iter()
+        .filter(|(name, value)| old.get(*name) != Some(value))
+        .map(|(name, value)| format_capability(name, value))
+        .collect::<Vec<_>>();
+
+    let server_prefix = state.server_name();
+    for (_, user) in state.users.lock()?.iter_mut() {
+        for name in &removed {
+            user.capabilities.remove(name);
+        }
+        if !user.capabilities.contains("cap-notify") {
+            continue;
+        }
+
+        let target = user.nickname.clone().unwrap_or_else(|| "*".to_string());
+        for (subcommand, list) in [("DEL", &removed), ("NEW", &added)] {
+            if !list.is_empty() {
+                let message = Message::new(
+                    Some(server_prefix.clone()),
+                    Command::Cap,
+                    &[&target, subcommand, &list.join(" ")],
+                );
+                user.send(&message)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn handle_cap(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: CAP LS 302
+    //          CAP REQ :invite-notify cap-notify
+    //          CAP END
+    let subcommand = message.params[0].to_uppercase();
+
+    let target = {
+        let mut lock = users.lock()?;
+        let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+
+        // Negotiating before registering holds off registration until CAP END
+        if !user.is_registered && matches!(subcommand.as_str(), "LS" | "REQ") {
+            user.cap_negotiating = true;
+        }
+
+        user.nickname.clone().unwrap_or_else(|| "*".to_string())
+    };
+    let reply = |subcommand: &str, list: &str| {
+        Message::new(
+            Some(server_prefix.to_string()),
+            Command::Cap,
+            &[&target, subcommand, list],
+        )
+    };
+
+    match subcommand.as_str() {
+        "LS" => {
+            let version = message
+                .params
+                .get(1)
+                .and_then(|version| version.parse::<u32>().ok())
+                .unwrap_or(0);
+
+            // Clients that support version 302 get capability values and implicitly get
+            // cap-notify
+            if version >= 302 {
+                let mut lock = users.lock()?;
+                let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+                user.cap_version = version;
+                user.capabilities.insert("cap-notify".to_string());
+            }
+
+            let capabilities = state
+                .capabilities
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(name, value)| {
+                    if version >= 302 {
+                        format_capability(name, value)
+                    } else {
+                        name.clone()
+                    }
+                })
+                .collect::<Vec<_>>();
+            send_list(state, user_id, "LS", &capabilities, version >= 302, &reply)?;
+        }
+        "LIST" => {
+            let capabilities = users
+                .lock()?
+                .get(&user_id)
+                .ok_or(ServerError::NoSuchUser)?
+                .capabilities
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>();
+            send_list(state, user_id, "LIST", &capabilities, true, &reply)?;
+        }
+        "REQ" => {
+            let requested = message.params.get(1).cloned().unwrap_or_default();
+
+            // Either every change in the request is applied or none are
+            let supported = state.capabilities.read().unwrap().clone();
+            let valid = requested
+                .split_whitespace()
+                .all(|name| supported.contains_key(name.trim_start_matches('-')));
+            if !valid {
+                send_to_user(&reply("NAK", &requested), users, user_id)?;
+                return Ok(CommandResponse::Continue);
+            }
+
+            {
+                let mut lock = users.lock()?;
+                let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+                for name in requested.split_whitespace() {
+                    match name.strip_prefix('-') {
+                        Some(name) => user.capabilities.remove(name),
+                        None => user.capabilities.insert(name.to_string()),
+                    };
+                }
+            }
+            send_to_user(&reply("ACK", &requested), users, user_id)?;
+        }
+        "END" => {
+            let was_negotiating = {
+                let mut lock = users.lock()?;
+                let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+                std::mem::replace(&mut user.cap_negotiating, false)
+            };
+            if was_negotiating {
+                return try_register(state, user_id);
+            }
+        }
+        _ => {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_INVALIDCAPCMD,
+                &[&target, &subcommand, "Invalid CAP subcommand."],
+            );
+            send_to_user(&response, users, user_id)?;
+        }
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Send a list of capabilities, split over several replies if it's too long. Every reply but the
+/// last is marked with `*` so the client knows there are more to come, which only clients that
+/// support multiline replies understand.
+fn send_list(
+    state: &ServerState,
+    user_id: Uuid,
+    subcommand: &str,
+    capabilities: &[String],
+    multiline: bool,
+    reply: &dyn Fn(&str, &str) -> Message,
+) -> Result<(), ServerError> {
+    let mut lines = if multiline {
+        join_list(capabilities, ' ')
+    } else {
+        vec![capabilities.join(" ")]
+    };
+    // An empty list still gets a reply
+    if lines.is_empty() {
+        lines.push(String::new());
+    }
+
+    let count = lines.len();
+    for (i, line) in lines.into_iter().enumerate() {
+        let mut message = reply(subcommand, &line);
+        if i + 1 < count {
+            message.params.insert(2, "*".to_string());
+        }
+        send_to_user(&message, &state.users, user_id)?;
+    }
+
+    Ok(())
+}
+
+fn format_capability(name: &str, value: &Option<String>) -> String {
+    match value {
+        Some(value) => format!("{}={}", name, value),
+        None => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::tests as state;
+
+    #[test]
+    fn supported_leaves_out_disabled_capabilities() {
+        let config = Config {
+            multiline_max_bytes: 1024,
+            multiline_max_lines: 10,
+            disabled_capabilities: vec!["invite-notify".to_string()],
+            ..Config::default()
+        };
+        let capabilities = supported(&config);
+        assert!(!capabilities.contains_key("invite-notify"));
+        assert_eq!(capabilities["sasl"].as_deref(), Some("PLAIN"));
+        assert_eq!(
+            capabilities["draft/multiline"].as_deref(),
+            Some("max-bytes=1024,max-lines=10")
+        );
+    }
+
+    #[test]
+    fn req_applies_every_change_or_none() {
+        let state = state::server(Config::default());
+        let (user_id, client) = state::connect(&state);
+        let cap = |line: &str| {
+            handle_cap(Message::from(line).unwrap(), &state, user_id).unwrap();
+            let mut capabilities = state.users.lock().unwrap()[&user_id]
+                .capabilities
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>();
+            capabilities.sort();
+            capabilities
+        };
+
+        assert_eq!(
+            cap("CAP REQ :batch echo-message"),
+            ["batch", "echo-message"]
+        );
+        assert_eq!(
+            cap("CAP REQ :-batch server-time"),
+            ["echo-message", "server-time"]
+        );
+        assert_eq!(
+            cap("CAP REQ :-echo-message unknown"),
+            ["echo-message", "server-time"]
+        );
+
+        let replies = state::disconnect(&state, user_id, client);
+        assert_eq!(
+            replies,
+            [
+                ":127.0.0.1 CAP * ACK :batch echo-message",
+                ":127.0.0.1 CAP * ACK :-batch server-time",
+                ":127.0.0.1 CAP * NAK :-echo-message unknown",
+            ]
+        );
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{
+    store,
+    user::{Channel, ChannelModes, Topic},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+
+/// Settings of registered channels as they were last saved, which the channels are recreated from
+/// when the server starts.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ChannelStore {
+    #[serde(skip)]
+    path: String,
+    channels: Vec<SavedChannel>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedChannel {
+    name: String,
+    /// Account that registered the channel
+    founder: String,
+    #[serde(default)]
+    topic: Option<Topic>,
+    #[serde(default)]
+    modes: ChannelModes,
+    #[serde(default)]
+    bans: Vec<String>,
+    #[serde(default)]
+    access: Vec<String>,
+}
+
+impl ChannelStore {
+    /// Read the channels stored at `path`. If the file doesn't exist yet, no channels are
+    /// registered.
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut channels: ChannelStore = store::load_json(path)?;
+        channels.path = path.to_string();
+        Ok(channels)
+    }
+
+    /// Create the registered channels with the settings they were last saved with.
+    pub fn restore(&self) -> Vec<Channel> {
+        self.channels
+            .iter()
+            .map(|saved| {
+                let mut channel = Channel::new(&saved.name);
+                channel.topic = Mutex::new(saved.topic.clone());
+                channel.modes = Mutex::new(saved.modes.clone());
+                channel.bans = Mutex::new(saved.bans.clone());
+                channel.founder = Mutex::new(Some(saved.founder.clone()));
+                channel.access = Mutex::new(saved.access.clone());
+                channel
+            })
+            .collect()
+    }
+
+    /// Save the settings of every registered channel among `channels`.
+    pub fn save(&mut self, channels: &[Arc<Channel>]) -> io::Result<()> {
+        self.channels = channels
+            .iter()
+            .filter_map(|channel| {
+                let founder = channel.founder.lock().unwrap().clone()?;
+                Some(SavedChannel {
+                    name: channel.name.clone(),
+                    founder,
+                    topic: channel.topic.lock().unwrap().clone(),
+                    modes: channel.modes.lock().unwrap().clone(),
+                    bans: channel.bans.lock().unwrap().clone(),
+                    access: channel.access.lock().unwrap().clone(),
+                })
+            })
+            .collect();
+        self.channels.sort_by(|a, b| a.name.cmp(&b.name));
+
+        store::save_json(&self.path, self)
+    }
+}
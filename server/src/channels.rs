@@ -0,0 +1,744 @@
+use crate::{
+    error::ServerError,
+    mask, query,
+    server::{
+        get_nickname_id, send_notice, send_to_channel, send_to_user, stamp_message, CommandResponse,
+    },
+    state::ServerState,
+    user::{Channel, Topic},
+};
+use chrono::Utc;
+use shared::message::{Command, Message, ReplyCode, Response};
+use std::sync::Arc;
+use uuid::Uuid;
+
+pub fn handle_join(
+    message: Message,
+    channels: Vec<String>,
+    keys: Vec<String>,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    // Example: JOIN #rust
+    //          JOIN #secret,#rust hunter2
+
+    // Users can only be in one channel at a time, so joining several leaves them in the last one
+    for (index, channel_name) in channels.iter().enumerate() {
+        join_channel(&message, channel_name, keys.get(index), state, user_id)?;
+    }
+    Ok(CommandResponse::Continue)
+}
+
+/// Join a single channel, giving `key` if it has one.
+fn join_channel(
+    message: &Message,
+    channel_name: &str,
+    key: Option<&String>,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Get a reference to the channel if it is in the channels table, otherwise create it
+    let (channel, created) = {
+        let mut lock = state.channels.lock().unwrap();
+        match lock.get(channel_name) {
+            Some(channel) => (channel.clone(), false),
+            None => {
+                let channel = Arc::new(Channel::new(channel_name));
+                lock.insert(channel_name.to_string(), channel.clone());
+                (channel, true)
+            }
+        }
+    };
+
+    let (already_joined, invited, member_count, user_mask, account) = {
+        let lock = users.lock()?;
+        let user = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+        let member_count = lock
+            .values()
+            .filter(|user| user.channel.as_ref() == Some(&channel))
+            .count();
+        (
+            user.channel.as_ref() == Some(&channel),
+            user.invites.contains(channel_name),
+            member_count,
+            user.prefix().unwrap(),
+            user.account.clone(),
+        )
+    };
+    if already_joined {
+        return Ok(CommandResponse::Continue);
+    }
+
+    // An invite lets the user past the channel's restrictions, apart from its bans
+    let modes = channel.modes.lock().unwrap().clone();
+    let is_banned = channel
+        .bans
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|mask| mask::matches(mask, &user_mask));
+    let error = if is_banned {
+        Some((ReplyCode::ERR_BANNEDFROMCHAN, "Cannot join channel (+b)."))
+    } else if invited {
+        None
+    } else if modes.invite_only {
+        Some((ReplyCode::ERR_INVITEONLYCHAN, "Cannot join channel (+i)."))
+    } else if modes.key.is_some() && modes.key.as_ref() != key {
+        Some((ReplyCode::ERR_BADCHANNELKEY, "Cannot join channel (+k)."))
+    } else if modes.limit.is_some_and(|limit| member_count >= limit) {
+        Some((ReplyCode::ERR_CHANNELISFULL, "Cannot join channel (+l)."))
+    } else {
+        None
+    };
+    if let Some((code, text)) = error {
+        let response = Response::new(server_prefix, code, &[channel_name, text]);
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    // Users can only be in one channel at a time, so leave the current one first
+    let previous_channel = users
+        .lock()?
+        .get(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .channel
+        .clone();
+    if let Some(previous_channel) = previous_channel {
+        leave_channel(state, user_id, &previous_channel, None)?;
+    }
+
+    {
+        let mut lock = users.lock()?;
+        let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+        user.invites.remove(channel_name);
+        user.channel = Some(channel.clone());
+    }
+
+    // Whoever creates a channel is its first operator. Registered channels also make their
+    // founder and the accounts on their access list operators.
+    if created || account.is_some_and(|account| channel.has_access(&account)) {
+        channel.operators.lock().unwrap().insert(user_id);
+    }
+
+    // Tell everyone in the channel, including the user, that they joined. The key is left out so
+    // it isn't shown to anyone.
+    let mut join = Message::new(message.prefix.clone(), Command::Join, &[channel_name]);
+    join.tags = message.tags.clone();
+    stamp_message(&mut join);
+
+    // Clients with extended-join also get the user's account and real name
+    let (account, realname) = {
+        let lock = users.lock()?;
+        let user = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+        (user.account.clone(), user.realname.clone())
+    };
+    let mut extended_join = Message::new(
+        message.prefix.clone(),
+        Command::Join,
+        &[
+            channel_name,
+            account.as_deref().unwrap_or("*"),
+            realname.as_deref().unwrap_or(""),
+        ],
+    );
+    extended_join.tags = join.tags.clone();
+
+    users
+        .lock()?
+        .values_mut()
+        .filter(|user| user.channel.as_ref() == Some(&channel))
+        .try_for_each(|user| {
+            if user.capabilities.contains("extended-join") {
+                user.send(&extended_join)
+            } else {
+                user.send(&join)
+            }
+        })?;
+
+    // The previous channel may have been left empty
+    state.remove_empty_channels()?;
+
+    let topic = channel.topic.lock().unwrap().clone();
+    if let Some(topic) = topic {
+        send_topic(state, user_id, channel_name, &topic)?;
+    }
+
+    let names = Message::new(None, Command::Names, &[channel_name]);
+    query::handle_names(names, state, user_id)
+}
+
+pub fn handle_part(
+    channels: Vec<String>,
+    reason: Option<String>,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: PART #rust :Goodbye!
+    for channel_name in channels {
+        let channel = state.channels.lock().unwrap().get(&channel_name).cloned();
+        let channel = match channel {
+            Some(channel) => channel,
+            None => {
+                let response = Response::new(
+                    server_prefix,
+                    ReplyCode::ERR_NOSUCHCHANNEL,
+                    &["The given channel was not found."],
+                );
+                send_to_user(&response, users, user_id)?;
+                continue;
+            }
+        };
+
+        let is_member = users
+            .lock()?
+            .get(&user_id)
+            .ok_or(ServerError::NoSuchUser)?
+            .channel
+            .as_ref()
+            == Some(&channel);
+        if !is_member {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOTONCHANNEL,
+                &[&channel_name, "You're not on that channel."],
+            );
+            send_to_user(&response, users, user_id)?;
+            continue;
+        }
+
+        leave_channel(state, user_id, &channel, reason.as_deref())?;
+    }
+    state.remove_empty_channels()?;
+
+    Ok(CommandResponse::Continue)
+}
+
+pub fn handle_invite(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: INVITE bob #rust
+    let (nickname, channel_name) = (message.params[0].clone(), message.params[1].clone());
+
+    let no_such_nick = || {
+        Response::new(
+            server_prefix,
+            ReplyCode::ERR_NOSUCHNICK,
+            &["The given nick was not found."],
+        )
+    };
+    let target_id = match get_nickname_id(&nickname, users)? {
+        Some(id) => id,
+        None => {
+            send_to_user(&no_such_nick(), users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    // Anyone can invite to a channel that doesn't exist yet. Otherwise, only members can invite,
+    // and only operators if the channel is invite-only.
+    let channel = state.channels.lock().unwrap().get(&channel_name).cloned();
+    if let Some(channel) = &channel {
+        let error = {
+            let lock = users.lock()?;
+            if lock
+                .get(&user_id)
+                .ok_or(ServerError::NoSuchUser)?
+                .channel
+                .as_ref()
+                != Some(channel)
+            {
+                Some(Response::new(
+                    server_prefix,
+                    ReplyCode::ERR_NOTONCHANNEL,
+                    &[&channel_name, "You're not on that channel."],
+                ))
+            } else if lock
+                .get(&target_id)
+                .is_some_and(|target| target.channel.as_ref() == Some(channel))
+            {
+                Some(Response::new(
+                    server_prefix,
+                    ReplyCode::ERR_USERONCHANNEL,
+                    &[&nickname, &channel_name, "is already on channel."],
+                ))
+            } else if channel.modes.lock().unwrap().invite_only && !channel.is_operator(user_id) {
+                Some(Response::new(
+                    server_prefix,
+                    ReplyCode::ERR_CHANOPRIVSNEEDED,
+                    &[&channel_name, "You're not channel operator."],
+                ))
+            } else {
+                None
+            }
+        };
+
+        if let Some(response) = error {
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    }
+
+    // The target may have left since their nickname was looked up
+    let is_away = users.lock()?.get_mut(&target_id).map(|target| {
+        target.invites.insert(channel_name.clone());
+        target.is_away
+    });
+    let is_away = match is_away {
+        Some(is_away) => is_away,
+        None => {
+            send_to_user(&no_such_nick(), users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    let response = Response::new(
+        server_prefix,
+        ReplyCode::RPL_INVITING,
+        &[&nickname, &channel_name],
+    );
+    send_to_user(&response, users, user_id)?;
+    if is_away {
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::RPL_AWAY,
+            &[&nickname, "The recipient is marked as away."],
+        );
+        send_to_user(&response, users, user_id)?;
+    }
+
+    let invite = Message::new(message.prefix, Command::Invite, &[&nickname, &channel_name]);
+    send_to_user(&invite, users, target_id)?;
+
+    // Let the channel's other operators know about the invite if they asked for invite-notify
+    if let Some(channel) = &channel {
+        users
+            .lock()?
+            .values_mut()
+            .filter(|user| user.id != user_id && user.id != target_id)
+            .filter(|user| user.channel.as_ref() == Some(channel) && channel.is_operator(user.id))
+            .filter(|user| user.capabilities.contains("invite-notify"))
+            .try_for_each(|user| user.send(&invite))?;
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+pub fn handle_topic(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: TOPIC #rust
+    //          TOPIC #rust :Rust programming language
+    let channel_name = message.params[0].clone();
+
+    let channel = state.channels.lock().unwrap().get(&channel_name).cloned();
+    let channel = match channel {
+        Some(channel) => channel,
+        None => {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOSUCHCHANNEL,
+                &["The given channel was not found."],
+            );
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    let text = match message.params.get(1) {
+        Some(text) => text.clone(),
+        None => {
+            let topic = channel.topic.lock().unwrap().clone();
+            match topic {
+                Some(topic) => send_topic(state, user_id, &channel_name, &topic)?,
+                None => {
+                    let response = Response::new(
+                        server_prefix,
+                        ReplyCode::RPL_NOTOPIC,
+                        &[&channel_name, "No topic is set."],
+                    );
+                    send_to_user(&response, users, user_id)?;
+                }
+            }
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    let (is_member, nickname) = {
+        let lock = users.lock()?;
+        let user = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+        (
+            user.channel.as_ref() == Some(&channel),
+            user.nickname.clone().unwrap(),
+        )
+    };
+    let error = if !is_member {
+        Some(Response::new(
+            server_prefix,
+            ReplyCode::ERR_NOTONCHANNEL,
+            &[&channel_name, "You're not on that channel."],
+        ))
+    } else if channel.modes.lock().unwrap().topic_lock && !channel.is_operator(user_id) {
+        Some(Response::new(
+            server_prefix,
+            ReplyCode::ERR_CHANOPRIVSNEEDED,
+            &[&channel_name, "You're not channel operator."],
+        ))
+    } else {
+        None
+    };
+    if let Some(response) = error {
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    // An empty topic clears it
+    *channel.topic.lock().unwrap() =
+        Some(text.clone())
+            .filter(|text| !text.is_empty())
+            .map(|text| Topic {
+                text,
+                set_by: nickname,
+                set_at: Utc::now().timestamp(),
+            });
+    if channel.is_registered() {
+        state.save_channels()?;
+    }
+
+    let topic = Message::new(message.prefix, Command::Topic, &[&channel_name, &text]);
+    send_to_channel(&topic, users, &channel)?;
+
+    Ok(CommandResponse::Continue)
+}
+
+pub fn handle_cregister(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: CREGISTER #rust
+    let channel_name = message.params[0].clone();
+
+    let channel = state.channels.lock().unwrap().get(&channel_name).cloned();
+    let account = users
+        .lock()?
+        .get(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .account
+        .clone();
+
+    // Only a channel operator that's logged in can register a channel, which makes their account
+    // its founder
+    let fail = |code: &str, text: &str| {
+        Message::new(
+            Some(server_prefix.to_string()),
+            Command::Fail,
+            &["CREGISTER", code, &channel_name, text],
+        )
+    };
+    let (channel, account) = match (channel, account) {
+        (None, _) => {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOSUCHCHANNEL,
+                &["The given channel was not found."],
+            );
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+        (Some(_), None) => {
+            let error = fail(
+                "ACCOUNT_REQUIRED",
+                "You need to be logged in to register a channel.",
+            );
+            send_to_user(&error, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+        (Some(channel), Some(account)) => (channel, account),
+    };
+
+    if !channel.is_operator(user_id) {
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::ERR_CHANOPRIVSNEEDED,
+            &[&channel_name, "You're not channel operator."],
+        );
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+    if channel.is_registered() {
+        let error = fail("ALREADY_REGISTERED", "That channel is already registered.");
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    *channel.founder.lock().unwrap() = Some(account.clone());
+    state.save_channels()?;
+    send_notice(
+        state,
+        user_id,
+        &format!("Registered {} to {}.", channel_name, account),
+    )?;
+
+    Ok(CommandResponse::Continue)
+}
+
+pub fn handle_access(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: ACCESS #rust LIST
+    //          ACCESS #rust ADD bob
+    //          ACCESS #rust DEL bob
+    let (channel_name, subcommand) = (message.params[0].clone(), message.params[1].to_uppercase());
+
+    let fail = |code: &str, text: &str| {
+        Message::new(
+            Some(server_prefix.to_string()),
+            Command::Fail,
+            &["ACCESS", code, &channel_name, text],
+        )
+    };
+    let channel = state.channels.lock().unwrap().get(&channel_name).cloned();
+    let channel = match channel {
+        Some(channel) if channel.is_registered() => channel,
+        _ => {
+            let error = fail("NOT_REGISTERED", "That channel isn't registered.");
+            send_to_user(&error, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    if subcommand == "LIST" {
+        let founder = channel.founder.lock().unwrap().clone().unwrap();
+        let access = channel.access.lock().unwrap().join(" ");
+        send_notice(
+            state,
+            user_id,
+            &format!("{} founder: {}", channel_name, founder),
+        )?;
+        send_notice(
+            state,
+            user_id,
+            &format!("{} access: {}", channel_name, access),
+        )?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    // Only the founder can change who has access
+    let account = users
+        .lock()?
+        .get(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .account
+        .clone();
+    let is_founder = account.is_some_and(|account| {
+        channel
+            .founder
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|founder| founder.eq_ignore_ascii_case(&account))
+    });
+    if !is_founder {
+        let error = fail(
+            "NOT_FOUNDER",
+            "Only the channel founder can change its access list.",
+        );
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let target = match message.params.get(2) {
+        Some(target) => target.clone(),
+        None => {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_NEEDMOREPARAMS,
+                &["ACCESS", "Not enough parameters."],
+            );
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    let notice = {
+        let mut access = channel.access.lock().unwrap();
+        match subcommand.as_str() {
+            "ADD" => {
+                if !access
+                    .iter()
+                    .any(|entry| entry.eq_ignore_ascii_case(&target))
+                {
+                    access.push(target.clone());
+                }
+                format!("Added {} to the {} access list.", target, channel_name)
+            }
+            "DEL" => {
+                access.retain(|entry| !entry.eq_ignore_ascii_case(&target));
+                format!("Removed {} from the {} access list.", target, channel_name)
+            }
+            _ => {
+                drop(access);
+                let error = fail("UNKNOWN_SUBCOMMAND", "Use LIST, ADD or DEL.");
+                send_to_user(&error, users, user_id)?;
+                return Ok(CommandResponse::Continue);
+            }
+        }
+    };
+    state.save_channels()?;
+    send_notice(state, user_id, &notice)?;
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Send the user a channel's topic, along with who set it and when.
+fn send_topic(
+    state: &ServerState,
+    user_id: Uuid,
+    channel_name: &str,
+    topic: &Topic,
+) -> Result<(), ServerError> {
+    let server_prefix = &state.server_name();
+
+    let response = Response::new(
+        server_prefix,
+        ReplyCode::RPL_TOPIC,
+        &[channel_name, &topic.text],
+    );
+    send_to_user(&response, &state.users, user_id)?;
+    let response = Response::new(
+        server_prefix,
+        ReplyCode::RPL_TOPICWHOTIME,
+        &[channel_name, &topic.set_by, &topic.set_at.to_string()],
+    );
+    send_to_user(&response, &state.users, user_id)
+}
+
+/// Remove the user from a channel, telling everyone in it with a PART.
+fn leave_channel(
+    state: &ServerState,
+    user_id: Uuid,
+    channel: &Arc<Channel>,
+    reason: Option<&str>,
+) -> Result<(), ServerError> {
+    let users = &state.users;
+
+    let prefix = users
+        .lock()?
+        .get(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .prefix();
+    let mut params = vec![channel.name.as_str()];
+    params.extend(reason);
+    let mut part = Message::new(prefix, Command::Part, &params);
+    stamp_message(&mut part);
+    send_to_channel(&part, users, channel)?;
+
+    channel.operators.lock().unwrap().remove(&user_id);
+    users
+        .lock()?
+        .get_mut(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .channel = None;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, state::tests as state};
+
+    #[test]
+    fn invite_does_not_bypass_bans() {
+        let state = state::server(Config::default());
+        let channel = Channel::new("#rust");
+        channel.modes.lock().unwrap().invite_only = true;
+        channel.bans.lock().unwrap().push("bob!*@*".to_string());
+        state
+            .channels
+            .lock()
+            .unwrap()
+            .insert("#rust".to_string(), Arc::new(channel));
+
+        let join = |user_id| {
+            let message = Message::from("JOIN #rust").unwrap();
+            state.handlers.dispatch(message, &state, user_id).unwrap();
+        };
+        let (bob, bob_client) = state::register(&state, "bob");
+        let (carol, carol_client) = state::register(&state, "carol");
+        for user_id in [bob, carol] {
+            let mut users = state.users.lock().unwrap();
+            let user = users.get_mut(&user_id).unwrap();
+            user.invites.insert("#rust".to_string());
+        }
+        join(bob);
+        join(carol);
+
+        let replies = state::disconnect(&state, bob, bob_client);
+        assert_eq!(replies, [":127.0.0.1 474 #rust :Cannot join channel (+b)."]);
+        let replies = state::disconnect(&state, carol, carol_client);
+        assert_eq!(replies[0], "JOIN #rust");
+    }
+
+    #[test]
+    fn invites_let_users_into_invite_only_channels() {
+        let state = state::server(Config::default());
+        let send = |line: &str, user_id| {
+            let message = Message::from(line).unwrap();
+            state.handlers.dispatch(message, &state, user_id).unwrap();
+        };
+        let (alice, alice_client) = state::register(&state, "alice");
+        let (bob, bob_client) = state::register(&state, "bob");
+        let (carol, carol_client) = state::register(&state, "carol");
+
+        send("JOIN #rust", alice);
+        state.channels.lock().unwrap()["#rust"]
+            .modes
+            .lock()
+            .unwrap()
+            .invite_only = true;
+        send("INVITE carol #rust", bob);
+        send("JOIN #rust", carol);
+        send("INVITE carol #rust", alice);
+        send("JOIN #rust", carol);
+
+        let replies = state::disconnect(&state, bob, bob_client);
+        assert_eq!(
+            replies,
+            [":127.0.0.1 442 #rust :You're not on that channel."]
+        );
+        let replies = state::disconnect(&state, alice, alice_client);
+        assert!(replies.contains(&":127.0.0.1 341 carol #rust".to_string()));
+        let replies = state::disconnect(&state, carol, carol_client);
+        assert_eq!(
+            replies[0],
+            ":127.0.0.1 473 #rust :Cannot join channel (+i)."
+        );
+        assert_eq!(replies[1], "INVITE carol #rust");
+        assert_eq!(replies[2], "JOIN #rust");
+    }
+}
@@ -0,0 +1,321 @@
+use crate::mask;
+use serde::Deserialize;
+use std::{fs, io::ErrorKind, path::Path, time::Duration};
+
+/// Server configuration, loaded from a TOML file at startup.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Name the server uses as the prefix of its own messages.
+    pub server_name: String,
+    /// Password every connection must send with PASS before registering, unless a more specific
+    /// listener or connection class password applies.
+    pub password: Option<String>,
+    pub listeners: Vec<Listener>,
+    pub classes: Vec<ConnectionClass>,
+    pub operators: Vec<Operator>,
+    /// File K-lines and D-lines are saved to
+    pub bans_file: String,
+    /// File registered accounts are saved to
+    pub accounts_file: String,
+    /// File the settings of registered channels are saved to
+    pub channels_file: String,
+    /// Seconds a user can keep a registered nickname without logging in to its account
+    pub nick_grace_period: u64,
+    /// What happens to users still using a registered nickname after the grace period
+    pub nick_enforcement: NickEnforcement,
+    /// Most messages kept for each channel and each pair of users, for CHATHISTORY
+    pub history_length: usize,
+    /// File message history is saved to, or `None` to only keep it in memory
+    pub history_file: Option<String>,
+    /// Most lines a `draft/multiline` message can have
+    pub multiline_max_lines: usize,
+    /// Most bytes of text a `draft/multiline` message can have, counting the line breaks
+    pub multiline_max_bytes: usize,
+    /// Most TAGMSGs a user can send per second. Any more are dropped, since they only carry
+    /// things like typing notifications.
+    pub tagmsg_rate_limit: usize,
+    /// Most nicknames a user can watch with MONITOR
+    pub monitor_limit: usize,
+    /// Most connections the server accepts at once
+    pub max_clients: usize,
+    /// Most connections accepted at once from a single IP address, unless the connection class
+    /// sets its own limit. The same goes for the settings below.
+    pub max_clients_per_ip: usize,
+    /// Most bytes waiting to be sent to a connection before it's closed for not reading them
+    pub sendq: usize,
+    /// Most bytes received from a connection and not handled yet before it's closed
+    pub recvq: usize,
+    /// Seconds a connection can be idle before it's sent a PING, and then has to answer it in
+    pub ping_frequency: u64,
+    pub flood: FloodLimits,
+    /// Capabilities the server won't offer with CAP, e.g. `["invite-notify"]`
+    pub disabled_capabilities: Vec<String>,
+}
+
+/// An address the server accepts connections on.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Listener {
+    pub address: String,
+    pub password: Option<String>,
+}
+
+/// A group of connections selected by the host they connect from or the account they log in to,
+/// with limits that replace the server-wide ones.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ConnectionClass {
+    pub name: String,
+    /// Host masks (e.g. `10.0.*`) a connection has to match to be placed in this class.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Accounts whose users are moved to this class when they log in
+    #[serde(default)]
+    pub accounts: Vec<String>,
+    pub password: Option<String>,
+    pub max_clients_per_ip: Option<usize>,
+    pub sendq: Option<usize>,
+    pub recvq: Option<usize>,
+    pub ping_frequency: Option<u64>,
+    pub flood: Option<FloodLimits>,
+}
+
+/// Limits applying to a connection, taken from its class or the server-wide settings.
+#[derive(Debug, Clone, Copy)]
+pub struct ClassLimits {
+    pub max_clients_per_ip: usize,
+    pub sendq: usize,
+    pub recvq: usize,
+    pub ping_frequency: Duration,
+    pub flood: FloodLimits,
+}
+
+/// How fast a connection can send commands. Commands cost tokens according to their weight, and
+/// ones sent without enough tokens wait until the connection has earned them back.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct FloodLimits {
+    /// Tokens a connection can spend at once, e.g. when it first connects
+    pub burst: f64,
+    /// Tokens a connection earns back each second
+    pub rate: f64,
+    /// Commands that can be waiting for tokens before the connection is closed for flooding
+    pub max_queued: usize,
+}
+
+/// Credentials and privileges of an IRC operator, used by OPER.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Operator {
+    pub name: String,
+    /// Bcrypt hash of the operator's password, as printed by `server --mkpasswd <password>`
+    pub password: String,
+    /// `user@host` masks the operator is allowed to log in from
+    #[serde(default = "any_host")]
+    pub hosts: Vec<String>,
+    #[serde(default)]
+    pub privileges: Vec<Privilege>,
+}
+
+/// Operator commands that have to be granted individually.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Privilege {
+    Kill,
+    Wallops,
+    Rehash,
+    Die,
+    Restart,
+    /// Setting and removing K-lines and D-lines
+    Ban,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NickEnforcement {
+    /// Change their nickname to a guest one
+    Rename,
+    /// Close their connection
+    Disconnect,
+}
+
+fn any_host() -> Vec<String> {
+    vec!["*@*".to_string()]
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server_name: "127.0.0.1".to_string(),
+            password: None,
+            listeners: vec![Listener {
+                address: "127.0.0.1:8080".to_string(),
+                password: None,
+            }],
+            classes: vec![],
+            operators: vec![],
+            bans_file: "bans.json".to_string(),
+            accounts_file: "accounts.json".to_string(),
+            channels_file: "channels.json".to_string(),
+            nick_grace_period: 60,
+            nick_enforcement: NickEnforcement::Rename,
+            history_length: 1000,
+            history_file: None,
+            multiline_max_lines: 100,
+            multiline_max_bytes: 4096,
+            tagmsg_rate_limit: 5,
+            monitor_limit: 100,
+            max_clients: 1024,
+            max_clients_per_ip: 10,
+            sendq: 100 * 1024,
+            recvq: 32 * 1024,
+            ping_frequency: 120,
+            flood: FloodLimits::default(),
+            disabled_capabilities: vec![],
+        }
+    }
+}
+
+impl Default for FloodLimits {
+    fn default() -> Self {
+        FloodLimits {
+            burst: 10.0,
+            rate: 1.0,
+            max_queued: 50,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from `path`. If the file doesn't exist, the default configuration is
+    /// used instead.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, Box<dyn std::error::Error>> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Ok(toml::from_str(&contents)?),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Return the connection class of a connection from `host`, logged in to `account` if it is.
+    /// Classes listing the account come first, then the first class whose host masks match.
+    pub fn class_for(&self, host: &str, account: Option<&str>) -> Option<&ConnectionClass> {
+        let by_account = account.and_then(|account| {
+            self.classes.iter().find(|class| {
+                class
+                    .accounts
+                    .iter()
+                    .any(|entry| entry.eq_ignore_ascii_case(account))
+            })
+        });
+        by_account.or_else(|| {
+            self.classes
+                .iter()
+                .find(|class| class.hosts.iter().any(|mask| mask::matches(mask, host)))
+        })
+    }
+
+    /// Return the limits of connections in a class, or of connections without one.
+    pub fn limits(&self, class: Option<&str>) -> ClassLimits {
+        let class = class.and_then(|name| self.classes.iter().find(|class| class.name == name));
+        ClassLimits {
+            max_clients_per_ip: class
+                .and_then(|class| class.max_clients_per_ip)
+                .unwrap_or(self.max_clients_per_ip),
+            sendq: class.and_then(|class| class.sendq).unwrap_or(self.sendq),
+            recvq: class.and_then(|class| class.recvq).unwrap_or(self.recvq),
+            ping_frequency: Duration::from_secs(
+                class
+                    .and_then(|class| class.ping_frequency)
+                    .unwrap_or(self.ping_frequency),
+            ),
+            flood: class.and_then(|class| class.flood).unwrap_or(self.flood),
+        }
+    }
+
+    /// Return the password a connection has to give to register. A connection class password
+    /// takes precedence over a listener password, which takes precedence over the server password.
+    pub fn required_password(&self, listener: &str, class: Option<&str>) -> Option<&str> {
+        let class_password = class
+            .and_then(|name| self.classes.iter().find(|class| class.name == name))
+            .and_then(|class| class.password.as_deref());
+        let listener_password = self
+            .listeners
+            .iter()
+            .find(|config| config.address == listener)
+            .and_then(|config| config.password.as_deref());
+
+        class_password
+            .or(listener_password)
+            .or(self.password.as_deref())
+    }
+
+    pub fn operator(&self, name: &str) -> Option<&Operator> {
+        self.operators.iter().find(|operator| operator.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn class_password_overrides_listener_and_server_passwords() {
+        let config: Config = toml::from_str(
+            r#"
+            password = "server"
+
+            [[listeners]]
+            address = "127.0.0.1:6667"
+            password = "listener"
+
+            [[listeners]]
+            address = "127.0.0.1:6668"
+
+            [[classes]]
+            name = "staff"
+            password = "class"
+
+            [[classes]]
+            name = "guests"
+            "#,
+        )
+        .unwrap();
+
+        let password = |listener, class| config.required_password(listener, class);
+        assert_eq!(password("127.0.0.1:6667", Some("staff")), Some("class"));
+        assert_eq!(password("127.0.0.1:6667", Some("guests")), Some("listener"));
+        assert_eq!(password("127.0.0.1:6667", None), Some("listener"));
+        assert_eq!(password("127.0.0.1:6668", None), Some("server"));
+        assert_eq!(
+            Config::default().required_password("127.0.0.1:8080", None),
+            None
+        );
+    }
+
+    #[test]
+    fn account_classes_come_before_host_classes() {
+        let config: Config = toml::from_str(
+            r#"
+            sendq = 1000
+
+            [[classes]]
+            name = "local"
+            hosts = ["127.*"]
+            sendq = 2000
+
+            [[classes]]
+            name = "staff"
+            accounts = ["Alice"]
+            "#,
+        )
+        .unwrap();
+
+        let class = |host, account| config.class_for(host, account).map(|class| &class.name[..]);
+        assert_eq!(class("127.0.0.1", None), Some("local"));
+        assert_eq!(class("127.0.0.1", Some("alice")), Some("staff"));
+        assert_eq!(class("10.0.0.1", Some("bob")), None);
+
+        assert_eq!(config.limits(Some("local")).sendq, 2000);
+        assert_eq!(config.limits(Some("staff")).sendq, 1000);
+        assert_eq!(config.limits(None).sendq, 1000);
+    }
+}
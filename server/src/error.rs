@@ -0,0 +1,57 @@
+use std::{fmt, io, sync::PoisonError};
+
+/// Errors that can happen while handling a client's commands.
+#[derive(Debug)]
+pub enum ServerError {
+    /// The client's connection ended without a QUIT
+    Disconnected,
+    /// Reading from a connection or saving state to a file failed
+    Io(io::Error),
+    /// State couldn't be serialized to be saved
+    Json(serde_json::Error),
+    /// A password couldn't be hashed
+    Hash(bcrypt::BcryptError),
+    /// A thread panicked while holding a lock on shared state
+    Poisoned,
+    /// The user a command came from left while it was being handled
+    NoSuchUser,
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Disconnected => write!(f, "Connection reset by peer"),
+            ServerError::Io(err) => write!(f, "{}", err),
+            ServerError::Json(err) => write!(f, "Couldn't serialize state: {}", err),
+            ServerError::Hash(err) => write!(f, "Couldn't hash password: {}", err),
+            ServerError::Poisoned => write!(f, "Shared state is poisoned"),
+            ServerError::NoSuchUser => write!(f, "User is no longer connected"),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+impl From<io::Error> for ServerError {
+    fn from(err: io::Error) -> Self {
+        ServerError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for ServerError {
+    fn from(err: serde_json::Error) -> Self {
+        ServerError::Json(err)
+    }
+}
+
+impl From<bcrypt::BcryptError> for ServerError {
+    fn from(err: bcrypt::BcryptError) -> Self {
+        ServerError::Hash(err)
+    }
+}
+
+impl<T> From<PoisonError<T>> for ServerError {
+    fn from(_: PoisonError<T>) -> Self {
+        ServerError::Poisoned
+    }
+}
@@ -0,0 +1,92 @@
+use crate::config::FloodLimits;
+use shared::message::Command;
+use std::time::{Duration, Instant};
+
+/// Token bucket limiting how fast a connection's commands are handled. It keeps track of the tokens
+/// spent rather than the ones left, so that it doesn't depend on the burst size, which can change
+/// with REHASH.
+#[derive(Debug)]
+pub struct TokenBucket {
+    spent: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        TokenBucket {
+            spent: 0.0,
+            updated: Instant::now(),
+        }
+    }
+
+    /// Give back the tokens earned since the last update.
+    fn refill(&mut self, limits: &FloodLimits) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.updated).as_secs_f64() * limits.rate;
+        self.spent = (self.spent - earned).max(0.0);
+        self.updated = now;
+    }
+
+    /// Spend the tokens for a command with the given weight if there are enough of them. Return
+    /// whether there were.
+    pub fn try_take(&mut self, limits: &FloodLimits, weight: f64) -> bool {
+        self.refill(limits);
+        // A command can't cost more than the whole bucket, or it could never be handled
+        let weight = weight.min(limits.burst);
+        if self.spent + weight <= limits.burst {
+            self.spent += weight;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Return how long until there are enough tokens for a command with the given weight.
+    pub fn time_until(&mut self, limits: &FloodLimits, weight: f64) -> Duration {
+        self.refill(limits);
+        let missing = self.spent + weight.min(limits.burst) - limits.burst;
+        if missing <= 0.0 || limits.rate <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / limits.rate)
+        }
+    }
+}
+
+/// Return how many tokens a command costs. Commands that take more work to answer or bother other
+/// users cost more, while keepalives are free so they're never held back.
+pub fn weight(command: &Command) -> f64 {
+    match command {
+        Command::Ping | Command::Pong => 0.0,
+        Command::TagMsg => 0.5,
+        Command::Join | Command::Part | Command::Nick | Command::Invite | Command::Monitor => 2.0,
+        Command::Who | Command::Names | Command::List | Command::ChatHistory | Command::Stats => {
+            3.0
+        }
+        Command::Register | Command::Cregister => 5.0,
+        _ => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttles_after_burst() {
+        let limits = FloodLimits {
+            burst: 3.0,
+            rate: 1.0,
+            max_queued: 10,
+        };
+        let mut bucket = TokenBucket::new();
+        assert!(bucket.try_take(&limits, 2.0));
+        assert!(bucket.try_take(&limits, 1.0));
+        assert!(!bucket.try_take(&limits, 1.0));
+        assert!(bucket.time_until(&limits, 1.0) > Duration::from_millis(900));
+
+        // Commands heavier than the whole bucket still go through once it's full again
+        bucket.updated -= Duration::from_secs(3);
+        assert!(bucket.try_take(&limits, 10.0));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::{
+    error::ServerError,
+    server::{send_to_user, CommandResponse},
+    state::ServerState,
+};
+use shared::{
+    command::ClientCommand,
+    message::{Command, Message, ReplyCode, Response},
+};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    mem::{self, Discriminant},
+};
+use uuid::Uuid;
+
+/// Handler taking the raw message of a command that doesn't have a typed form.
+pub type MessageHandler = fn(Message, &ServerState, Uuid) -> Result<CommandResponse, ServerError>;
+
+/// Handler taking a command's typed form along with the message it came from.
+pub type CommandHandler =
+    fn(Message, ClientCommand, &ServerState, Uuid) -> Result<CommandResponse, ServerError>;
+
+/// Something that handles a kind of command once the user meets its requirements.
+pub trait Handler: Send + Sync {
+    fn requirements(&self) -> Requirements;
+
+    /// Handle a command. `command` is its typed form, or `ClientCommand::Other` if it doesn't have
+    /// one.
+    fn handle(
+        &self,
+        message: Message,
+        command: ClientCommand,
+        state: &ServerState,
+        user_id: Uuid,
+    ) -> Result<CommandResponse, ServerError>;
+}
+
+/// What a user needs for a command of theirs to be handled, checked before the handler is called.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Requirements {
+    /// Whether the user has to have registered
+    pub registered: bool,
+    /// Whether the user has to be logged in as an operator. Operator privileges are up to the
+    /// handler.
+    pub oper: bool,
+    /// Fewest parameters the command can have
+    pub min_params: usize,
+    /// Capability the user has to have enabled, without which the command doesn't exist to them
+    pub capability: Option<&'static str>,
+}
+
+/// Handlers of the commands clients can send, keyed by command.
+#[derive(Default)]
+pub struct Registry {
+    handlers: HashMap<Discriminant<Command>, Box<dyn Handler>>,
+}
+
+/// A handler made from a function.
+struct Route<F> {
+    requirements: Requirements,
+    handler: F,
+}
+
+impl Requirements {
+    /// Requirements of a command that can be sent before registering.
+    pub fn none() -> Self {
+        Requirements::default()
+    }
+
+    /// Requirements of a command that can only be sent after registering.
+    pub fn registered() -> Self {
+        Requirements {
+            registered: true,
+            ..Requirements::default()
+        }
+    }
+
+    pub fn oper(self) -> Self {
+        Requirements { oper: true, ..self }
+    }
+
+    pub fn params(self, min_params: usize) -> Self {
+        Requirements { min_params, ..self }
+    }
+
+    pub fn capability(self, capability: &'static str) -> Self {
+        Requirements {
+            capability: Some(capability),
+            ..self
+        }
+    }
+}
+
+impl Registry {
+    /// Handle `command` with `handler`, replacing any handler it already had.
+    pub fn add(&mut self, command: Command, handler: impl Handler + 'static) {
+        self.handlers
+            .insert(mem::discriminant(&command), Box::new(handler));
+    }
+
+    /// Handle `command` with a function taking its raw message.
+    pub fn route(&mut self, command: Command, requirements: Requirements, handler: MessageHandler) {
+        self.add(
+            command,
+            Route {
+                requirements,
+                handler,
+            },
+        );
+    }
+
+    /// Handle `command` with a function taking its typed form.
+    pub fn route_typed(
+        &mut self,
+        command: Command,
+        requirements: Requirements,
+        handler: CommandHandler,
+    ) {
+        self.add(
+            command,
+            Route {
+                requirements,
+                handler,
+            },
+        );
+    }
+
+    /// Pass a message to the handler of its command. The user is sent the numeric reply for the
+    /// first requirement they don't meet instead, or for whatever the typed form of the command is
+    /// missing.
+    pub fn dispatch(
+        &self,
+        message: Message,
+        state: &ServerState,
+        user_id: Uuid,
+    ) -> Result<CommandResponse, ServerError> {
+        let users = &state.users;
+        let server_prefix = &state.server_name();
+        let unknown = || {
+            Response::new(
+                server_prefix,
+                ReplyCode::ERR_UNKNOWNCOMMAND,
+                &["Unknown command."],
+            )
+        };
+
+        let handler = match self.handlers.get(&mem::discriminant(&message.command)) {
+            Some(handler) => handler,
+            None => {
+                send_to_user(&unknown(), users, user_id)?;
+                return Ok(CommandResponse::Continue);
+            }
+        };
+
+        let requirements = handler.requirements();
+        let (is_registered, is_oper, has_capability) = {
+            let lock = users.lock()?;
+            let user = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+            (
+                user.is_registered,
+                user.operator.is_some(),
+                requirements
+                    .capability
+                    .is_none_or(|capability| user.capabilities.contains(capability)),
+            )
+        };
+        let error = if requirements.registered && !is_registered {
+            Some(Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOTREGISTERED,
+                &["You have not registered."],
+            ))
+        } else if !has_capability {
+            Some(unknown())
+        } else if requirements.oper && !is_oper {
+            Some(Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOPRIVILEGES,
+                &["Permission denied: you aren't an IRC operator."],
+            ))
+        } else if message.params.len() < requirements.min_params {
+            Some(Response::new(
+                server_prefix,
+                ReplyCode::ERR_NEEDMOREPARAMS,
+                &[
+                    &message.command.to_string().to_uppercase(),
+                    "Not enough parameters.",
+                ],
+            ))
+        } else {
+            None
+        };
+        if let Some(response) = error {
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+
+        let command = match ClientCommand::try_from(&message) {
+            Ok(command) => command,
+            Err(error) => {
+                if let Some(response) = error.reply(server_prefix) {
+                    send_to_user(&response, users, user_id)?;
+                }
+                return Ok(CommandResponse::Continue);
+            }
+        };
+        handler.handle(message, command, state, user_id)
+    }
+}
+
+impl Handler for Route<MessageHandler> {
+    fn requirements(&self) -> Requirements {
+        self.requirements
+    }
+
+    fn handle(
+        &self,
+        message: Message,
+        _command: ClientCommand,
+        state: &ServerState,
+        user_id: Uuid,
+    ) -> Result<CommandResponse, ServerError> {
+        (self.handler)(message, state, user_id)
+    }
+}
+
+impl Handler for Route<CommandHandler> {
+    fn requirements(&self) -> Requirements {
+        self.requirements
+    }
+
+    fn handle(
+        &self,
+        message: Message,
+        command: ClientCommand,
+        state: &ServerState,
+        user_id: Uuid,
+    ) -> Result<CommandResponse, ServerError> {
+        (self.handler)(message, command, state, user_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, state::tests as state};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    /// Handler counting how many times it was called.
+    struct Counter(Arc<AtomicUsize>);
+
+    impl Handler for Counter {
+        fn requirements(&self) -> Requirements {
+            Requirements::registered().oper().params(1)
+        }
+
+        fn handle(
+            &self,
+            _message: Message,
+            _command: ClientCommand,
+            _state: &ServerState,
+            _user_id: Uuid,
+        ) -> Result<CommandResponse, ServerError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(CommandResponse::Continue)
+        }
+    }
+
+    #[test]
+    fn checks_requirements_before_handling() {
+        let state = state::server(Config::default());
+        let (user_id, client) = state::connect(&state);
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut registry = Registry::default();
+        registry.add(Command::Kill, Counter(calls.clone()));
+        let dispatch = |line: &str| {
+            let message = Message::from(line).unwrap();
+            registry.dispatch(message, &state, user_id).unwrap();
+        };
+
+        dispatch("KILL bob");
+        state
+            .users
+            .lock()
+            .unwrap()
+            .get_mut(&user_id)
+            .unwrap()
+            .is_registered = true;
+        dispatch("KILL bob");
+        state
+            .users
+            .lock()
+            .unwrap()
+            .get_mut(&user_id)
+            .unwrap()
+            .operator = Some("admin".to_string());
+        dispatch("KILL");
+        dispatch("KILL bob");
+        dispatch("LIST");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let replies = state::disconnect(&state, user_id, client);
+        let codes = replies
+            .iter()
+            .map(|line| line.split(' ').nth(1).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(codes, ["451", "481", "461", "421"]);
+    }
+}
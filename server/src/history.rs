@@ -0,0 +1,703 @@
+use crate::{
+    error::ServerError,
+    server::{send_to_user, stamp_message, CommandResponse},
+    state::{ServerState, UserTable},
+    store,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use shared::message::{Batch, Command, Message};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+use uuid::Uuid;
+
+/// Most messages a client can fetch with one CHATHISTORY.
+const MAX_QUERY_LIMIT: usize = 100;
+/// How often the history is written to its file if it changed.
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Messages sent to each channel and between each pair of accounts, kept so clients that
+/// reconnect can fetch what they missed with CHATHISTORY.
+#[derive(Debug, Default)]
+pub struct History {
+    /// File the history is saved to, if it's kept across restarts
+    path: Option<String>,
+    /// Most messages kept for each target
+    length: usize,
+    /// Messages keyed by target, oldest first
+    targets: HashMap<String, Vec<Message>>,
+    /// Whether messages were added or removed since the history was last saved
+    changed: bool,
+}
+
+/// How history is saved to disk. Messages are kept as raw lines, tags included.
+#[derive(Default, Serialize, Deserialize)]
+struct SavedHistory {
+    targets: BTreeMap<String, Vec<String>>,
+}
+
+/// A point in a target's history, given as `msgid=<id>` or `timestamp=<time>`.
+#[derive(Debug, PartialEq)]
+pub enum Selector {
+    MsgId(String),
+    Timestamp(DateTime<Utc>),
+}
+
+/// Which messages a CHATHISTORY command asks for.
+#[derive(Debug, PartialEq)]
+pub enum Query {
+    /// The newest messages, only counting the ones after the selector if there is one
+    Latest(Option<Selector>),
+    Before(Selector),
+    After(Selector),
+    Around(Selector),
+    Between(Selector, Selector),
+}
+
+impl History {
+    /// Read the history stored at `path`, if there is one. If the file doesn't exist yet, the
+    /// history starts out empty.
+    pub fn load(path: Option<&str>, length: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let saved: SavedHistory = match path {
+            Some(path) => store::load_json(path)?,
+            None => SavedHistory::default(),
+        };
+
+        let targets = saved
+            .targets
+            .into_iter()
+            .map(|(key, lines)| {
+                let messages = lines
+                    .iter()
+                    .filter_map(|line| Message::from(line).ok())
+                    .collect();
+                (key, messages)
+            })
+            .collect();
+        Ok(History {
+            path: path.map(String::from),
+            length,
+            targets,
+            changed: false,
+        })
+    }
+
+    /// Write the history to its file if it has one and it changed since it was last saved. The
+    /// lock is only held while the messages are copied, not while the file is written.
+    pub fn save(history: &Mutex<History>) -> io::Result<()> {
+        let (path, saved) = {
+            let mut history = history.lock().unwrap();
+            let path = match &history.path {
+                Some(path) if history.changed => path.clone(),
+                _ => return Ok(()),
+            };
+            let saved = SavedHistory {
+                targets: history
+                    .targets
+                    .iter()
+                    .map(|(key, messages)| {
+                        let lines = messages.iter().map(Message::to_string).collect();
+                        (key.clone(), lines)
+                    })
+                    .collect(),
+            };
+            history.changed = false;
+            (path, saved)
+        };
+
+        // Try again next time if the file couldn't be written
+        store::save_json(&path, &saved).inspect_err(|_| history.lock().unwrap().changed = true)
+    }
+
+    /// Save the history every `SAVE_INTERVAL` if it changed. This runs on its own thread for as
+    /// long as the server does, and the history is saved one last time when it shuts down.
+    pub fn save_periodically(state: Arc<ServerState>) {
+        loop {
+            thread::sleep(SAVE_INTERVAL);
+            if let Err(err) = History::save(&state.history) {
+                println!("Couldn't save message history: {err}");
+            }
+        }
+    }
+
+    /// Add a message that was sent to a target, dropping the oldest one if there are too many.
+    /// The message has to have been stamped with `time` and `msgid` tags.
+    pub fn add(&mut self, key: &str, message: Message) {
+        let messages = self.targets.entry(key.to_string()).or_default();
+        messages.push(message);
+        let excess = messages.len().saturating_sub(self.length);
+        messages.drain(..excess);
+        self.changed = true;
+    }
+
+    /// Return the message sent to a target with the given `msgid`, or its first line if it was a
+    /// multi-line message.
+    pub fn get(&self, key: &str, msgid: &str) -> Option<&Message> {
+        self.targets
+            .get(key)?
+            .iter()
+            .find(|message| message.tags.get("msgid").map(String::as_str) == Some(msgid))
+    }
+
+    /// Remove the message sent to a target with the given `msgid`, if there is one, along with
+    /// every other line of it if it was a multi-line message.
+    pub fn remove(&mut self, key: &str, msgid: &str) {
+        if let Some(messages) = self.targets.get_mut(key) {
+            messages.retain(|message| message.tags.get("msgid").map(String::as_str) != Some(msgid));
+        }
+        self.changed = true;
+    }
+
+    /// Return up to `limit` messages sent to a target, oldest first.
+    pub fn query(&self, key: &str, query: &Query, limit: usize) -> Vec<Message> {
+        let messages = match self.targets.get(key) {
+            Some(messages) => messages,
+            None => return vec![],
+        };
+
+        // Each selector splits the history into the messages before it and the ones after it
+        let split = |selector: &Selector| -> Option<(usize, usize)> {
+            match selector {
+                // The lines of a multi-line message all have its msgid
+                Selector::MsgId(id) => {
+                    let has_id = |message: &Message| message.tags.get("msgid") == Some(id);
+                    Some((
+                        messages.iter().position(has_id)?,
+                        messages.iter().rposition(has_id)? + 1,
+                    ))
+                }
+                Selector::Timestamp(time) => Some((
+                    messages.partition_point(|message| message_time(message) < Some(*time)),
+                    messages.partition_point(|message| message_time(message) <= Some(*time)),
+                )),
+            }
+        };
+        let latest = |range: &[Message]| range[range.len().saturating_sub(limit)..].to_vec();
+        let earliest = |range: &[Message]| range[..range.len().min(limit)].to_vec();
+
+        match query {
+            Query::Latest(None) => latest(messages),
+            Query::Latest(Some(selector)) => split(selector)
+                .map(|(_, after)| latest(&messages[after..]))
+                .unwrap_or_default(),
+            Query::Before(selector) => split(selector)
+                .map(|(before, _)| latest(&messages[..before]))
+                .unwrap_or_default(),
+            Query::After(selector) => split(selector)
+                .map(|(_, after)| earliest(&messages[after..]))
+                .unwrap_or_default(),
+            Query::Around(selector) => split(selector)
+                .map(|(before, _)| {
+                    let start = before.saturating_sub(limit / 2);
+                    earliest(&messages[start..])
+                })
+                .unwrap_or_default(),
+            // Messages come from the end of the range nearest to the first selector
+            Query::Between(first, second) => match (split(first), split(second)) {
+                (Some((first_before, first_after)), Some((second_before, second_after))) => {
+                    if first_before <= second_before {
+                        earliest(&messages[first_after..second_before.max(first_after)])
+                    } else {
+                        latest(&messages[second_after..first_before.max(second_after)])
+                    }
+                }
+                _ => vec![],
+            },
+        }
+    }
+}
+
+impl Selector {
+    pub fn parse(text: &str) -> Option<Selector> {
+        match text.split_once('=')? {
+            ("msgid", id) => Some(Selector::MsgId(id.to_string())),
+            ("timestamp", time) => parse_time(time).map(Selector::Timestamp),
+            _ => None,
+        }
+    }
+}
+
+pub fn handle_chathistory(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: CHATHISTORY LATEST #rust * 50
+    //          CHATHISTORY BEFORE bob timestamp=2023-01-01T00:00:00.000Z 20
+    //          CHATHISTORY BETWEEN #rust msgid=abc msgid=def 100
+    let fail = |code: &str, context: &str, text: &str| {
+        Message::new(
+            Some(server_prefix.to_string()),
+            Command::Fail,
+            &["CHATHISTORY", code, context, text],
+        )
+    };
+
+    let (account, channel, has_caps) = {
+        let lock = users.lock()?;
+        let user = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+        (
+            user.account.clone(),
+            user.channel.clone(),
+            user.capabilities.contains("message-tags") && user.capabilities.contains("server-time"),
+        )
+    };
+
+    // Without tags, clients couldn't tell when history messages were sent or where they end
+    if !has_caps {
+        let error = fail(
+            "NEED_CAPABILITIES",
+            "*",
+            "CHATHISTORY needs the message-tags and server-time capabilities.",
+        );
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let subcommand = message
+        .params
+        .first()
+        .map(|subcommand| subcommand.to_uppercase())
+        .unwrap_or_default();
+    let param_count = if subcommand == "BETWEEN" { 5 } else { 4 };
+    if message.params.len() < param_count {
+        let error = fail("NEED_MORE_PARAMS", &subcommand, "Not enough parameters.");
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let target = &message.params[1];
+    let selector = Selector::parse(&message.params[2]);
+    let query = match subcommand.as_str() {
+        "LATEST" if message.params[2] == "*" => Some(Query::Latest(None)),
+        "LATEST" => selector.map(|selector| Query::Latest(Some(selector))),
+        "BEFORE" => selector.map(Query::Before),
+        "AFTER" => selector.map(Query::After),
+        "AROUND" => selector.map(Query::Around),
+        "BETWEEN" => selector
+            .zip(Selector::parse(&message.params[3]))
+            .map(|(first, second)| Query::Between(first, second)),
+        _ => {
+            let error = fail("UNKNOWN_COMMAND", &subcommand, "Unknown subcommand.");
+            send_to_user(&error, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+    let limit = message
+        .params
+        .last()
+        .and_then(|limit| limit.parse::<usize>().ok())
+        .filter(|limit| (1..=MAX_QUERY_LIMIT).contains(limit));
+    let (query, limit) = match (query, limit) {
+        (Some(query), Some(limit)) => (query, limit),
+        _ => {
+            let error = fail("INVALID_PARAMS", &subcommand, "Invalid selector or limit.");
+            send_to_user(&error, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    // Users can only read the history of the channel they're in and the private messages of the
+    // account they're logged in to
+    let key = if target.starts_with('#') {
+        let is_member = channel.is_some_and(|channel| channel.name.eq_ignore_ascii_case(target));
+        if !is_member {
+            let error = fail(
+                "INVALID_TARGET",
+                &subcommand,
+                "You can't fetch the history of that channel.",
+            );
+            send_to_user(&error, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+        channel_key(target)
+    } else if let Some(account) = &account {
+        direct_key(account, &target_account(state, target)?)
+    } else {
+        let error = fail(
+            "INVALID_TARGET",
+            &subcommand,
+            "You have to be logged in to fetch private messages.",
+        );
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    };
+
+    let messages = state.history.lock().unwrap().query(&key, &query, limit);
+
+    // The messages are sent as a batch, so clients can tell them apart from new ones
+    let batch = Batch::new("chathistory", &[target]);
+    users
+        .lock()?
+        .get_mut(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .send_batch(server_prefix, &batch, messages)?;
+
+    Ok(CommandResponse::Continue)
+}
+
+pub fn handle_redact(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: REDACT #rust 6d2fd8a4b3a24cd39d2ab1c2c5a8f1e0 :Pasted a password
+    let (target, msgid) = (message.params[0].clone(), message.params[1].clone());
+
+    let fail = |code: &str, text: &str| {
+        Message::new(
+            Some(server_prefix.to_string()),
+            Command::Fail,
+            &["REDACT", code, &target, &msgid, text],
+        )
+    };
+
+    let (prefix, account) = {
+        let lock = users.lock()?;
+        let user = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+        (user.prefix(), user.account.clone())
+    };
+
+    // Channel operators can redact anyone's messages in their channel. Otherwise, only the author
+    // of a message can redact it. Private messages are kept by account, so redacting one takes
+    // being logged in to it.
+    let (key, channel, is_operator, recipient) = if target.starts_with('#') {
+        let channel = state.channels.lock().unwrap().get(&target).cloned();
+        match channel {
+            Some(channel) => {
+                let is_operator = channel.is_operator(user_id);
+                (channel_key(&channel.name), Some(channel), is_operator, None)
+            }
+            None => {
+                let error = fail("INVALID_TARGET", "The given channel was not found.");
+                send_to_user(&error, users, user_id)?;
+                return Ok(CommandResponse::Continue);
+            }
+        }
+    } else if let Some(account) = &account {
+        let recipient = target_account(state, &target)?;
+        (
+            direct_key(account, &recipient),
+            None,
+            false,
+            Some(recipient),
+        )
+    } else {
+        let error = fail(
+            "INVALID_TARGET",
+            "You have to be logged in to redact private messages.",
+        );
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    };
+
+    let original = state.history.lock().unwrap().get(&key, &msgid).cloned();
+    let original = match original {
+        Some(original) => original,
+        None => {
+            let error = fail("UNKNOWN_MSGID", "There's no message with that ID.");
+            send_to_user(&error, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    // Messages sent while logged in belong to the account, so they can be redacted after changing
+    // nicknames or reconnecting
+    let is_author = match original.tags.get("account") {
+        Some(author) => account
+            .as_ref()
+            .is_some_and(|account| account.eq_ignore_ascii_case(author)),
+        None => original.prefix == prefix,
+    };
+    if !is_author && !is_operator {
+        let error = fail("REDACT_FORBIDDEN", "You can't redact that message.");
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    state.history.lock().unwrap().remove(&key, &msgid);
+
+    // Tell the users who could have seen the message, if their client understands redaction
+    let mut redact = message;
+    stamp_message(&mut redact);
+    let mut lock = users.lock()?;
+    for user in lock.values_mut() {
+        let could_see = match &channel {
+            Some(channel) => user.channel.as_ref() == Some(channel),
+            // Both sides of a private conversation, on every connection logged in to them
+            None => user.account.as_ref().is_some_and(|user_account| {
+                [&account, &recipient]
+                    .into_iter()
+                    .flatten()
+                    .any(|account| account.eq_ignore_ascii_case(user_account))
+            }),
+        };
+        if could_see && user.capabilities.contains("draft/message-redaction") {
+            user.send(&redact)?;
+        }
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Return the history key for messages sent to a channel.
+pub fn channel_key(channel: &str) -> String {
+    channel.to_lowercase()
+}
+
+/// Return the history key for private messages between two accounts, which is the same whichever
+/// of them sent the message.
+pub fn direct_key(account: &str, other: &str) -> String {
+    let mut accounts = [account.to_lowercase(), other.to_lowercase()];
+    accounts.sort();
+    accounts.join(" ")
+}
+
+/// Return the history key for private messages between two connected users. Private messages are
+/// kept by account rather than nickname, so that taking someone's nickname doesn't give access to
+/// them, which means they're only kept if both users are logged in.
+pub fn users_direct_key(
+    users: &UserTable,
+    user_id: Uuid,
+    other_id: Uuid,
+) -> Result<Option<String>, ServerError> {
+    let lock = users.lock()?;
+    let account = |id| lock.get(&id).and_then(|user| user.account.clone());
+    Ok(account(user_id)
+        .zip(account(other_id))
+        .map(|(account, other)| direct_key(&account, &other)))
+}
+
+/// Return the account meant by the target of a CHATHISTORY or REDACT for private messages: the
+/// account of the user with that nickname if they're logged in, or otherwise the account of that
+/// name, for when they aren't connected.
+fn target_account(state: &ServerState, target: &str) -> Result<String, ServerError> {
+    let account = state
+        .users
+        .lock()?
+        .values()
+        .find(|user| user.nickname.as_deref() == Some(target))
+        .and_then(|user| user.account.clone());
+    Ok(account.unwrap_or_else(|| target.to_string()))
+}
+
+fn message_time(message: &Message) -> Option<DateTime<Utc>> {
+    parse_time(message.tags.get("time")?)
+}
+
+fn parse_time(time: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(time)
+        .ok()
+        .map(|time| time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, server::handle_message, state::tests as state};
+    use std::net::TcpStream;
+
+    fn history() -> History {
+        let mut history = History {
+            length: 10,
+            ..History::default()
+        };
+        for i in 0..5 {
+            let message = Message::from(&format!(
+                "@msgid={i};time=2023-01-01T00:00:0{i}.000Z :alice PRIVMSG #rust :{i}"
+            ))
+            .unwrap();
+            history.add("#rust", message);
+        }
+        history
+    }
+
+    fn texts(messages: Vec<Message>) -> Vec<String> {
+        messages
+            .into_iter()
+            .map(|message| message.params[1].clone())
+            .collect()
+    }
+
+    #[test]
+    fn queries_around_selectors() {
+        let history = history();
+        let msgid = |id: &str| Selector::MsgId(id.to_string());
+
+        assert_eq!(
+            texts(history.query("#rust", &Query::Latest(None), 2)),
+            ["3", "4"]
+        );
+        assert_eq!(
+            texts(history.query("#rust", &Query::Before(msgid("3")), 2)),
+            ["1", "2"]
+        );
+        assert_eq!(
+            texts(history.query("#rust", &Query::After(msgid("1")), 2)),
+            ["2", "3"]
+        );
+        assert_eq!(
+            texts(history.query("#rust", &Query::Around(msgid("2")), 3)),
+            ["1", "2", "3"]
+        );
+        let between = Query::Between(msgid("4"), msgid("0"));
+        assert_eq!(texts(history.query("#rust", &between, 2)), ["2", "3"]);
+
+        let time = Selector::parse("timestamp=2023-01-01T00:00:02.000Z").unwrap();
+        assert_eq!(
+            texts(history.query("#rust", &Query::After(time), 10)),
+            ["3", "4"]
+        );
+    }
+
+    #[test]
+    fn saves_only_after_changes() {
+        let path = std::env::temp_dir().join(format!("history-{}.json", Uuid::new_v4()));
+        let history = Mutex::new(History {
+            path: Some(path.to_str().unwrap().to_string()),
+            ..history()
+        });
+
+        History::save(&history).unwrap();
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+        History::save(&history).unwrap();
+        assert!(!path.exists());
+
+        history.lock().unwrap().remove("#rust", "0");
+        History::save(&history).unwrap();
+        let saved = History::load(path.to_str(), 10).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            texts(saved.query("#rust", &Query::Latest(None), 10)),
+            ["1", "2", "3", "4"]
+        );
+    }
+
+    /// Add a user with the capabilities CHATHISTORY and REDACT need, logged in to `account` if
+    /// given.
+    fn login(state: &ServerState, nickname: &str, account: Option<&str>) -> (Uuid, TcpStream) {
+        let (user_id, client) = state::register(state, nickname);
+        let mut users = state.users.lock().unwrap();
+        let user = users.get_mut(&user_id).unwrap();
+        user.account = account.map(String::from);
+        for capability in [
+            "batch",
+            "message-tags",
+            "server-time",
+            "draft/chathistory",
+            "draft/message-redaction",
+        ] {
+            user.capabilities.insert(capability.to_string());
+        }
+        drop(users);
+        (user_id, client)
+    }
+
+    #[test]
+    fn private_history_is_only_given_to_the_account_it_belongs_to() {
+        let state = state::server(Config::default());
+        let send = |line: &str, user_id| {
+            let message = Message::from(line).unwrap();
+            state.handlers.dispatch(message, &state, user_id).unwrap();
+        };
+        let (alice, alice_client) = login(&state, "alice", Some("alice"));
+        let (bob, bob_client) = login(&state, "bob", Some("bob"));
+        send("PRIVMSG bob :The password is hunter2", alice);
+        state::disconnect(&state, alice, alice_client);
+        state::disconnect(&state, bob, bob_client);
+
+        let msgid = state.history.lock().unwrap().targets["alice bob"][0].tags["msgid"].clone();
+
+        // Whoever takes alice's nickname without her account can't read or redact her messages
+        for account in [None, Some("mallory")] {
+            let (impostor, client) = login(&state, "alice", account);
+            send("CHATHISTORY LATEST bob * 50", impostor);
+            send(&format!("REDACT bob {msgid} :Gone"), impostor);
+            let replies = state::disconnect(&state, impostor, client);
+            assert!(replies.iter().all(|line| !line.contains("hunter2")));
+            assert!(replies
+                .last()
+                .unwrap()
+                .starts_with(":127.0.0.1 FAIL REDACT "));
+        }
+        assert!(state
+            .history
+            .lock()
+            .unwrap()
+            .get("alice bob", &msgid)
+            .is_some());
+
+        let (alice, client) = login(&state, "alice2", Some("alice"));
+        send("CHATHISTORY LATEST bob * 50", alice);
+        let replies = state::disconnect(&state, alice, client);
+        assert!(replies[1].ends_with("PRIVMSG bob :The password is hunter2"));
+    }
+
+    #[test]
+    fn redactions_are_limited_to_authors_and_channel_operators() {
+        let state = state::server(Config::default());
+        let send = |line: &str, user_id| {
+            handle_message(Message::from(line).unwrap(), &state, user_id).unwrap();
+        };
+        // The first to join gets operator status
+        let (bob, bob_client) = login(&state, "bob", Some("bob"));
+        let (alice, alice_client) = login(&state, "alice", Some("alice"));
+        let (carol, carol_client) = login(&state, "carol", None);
+        let (dave, dave_client) = login(&state, "dave", None);
+        for user_id in [bob, alice, carol, dave] {
+            send("JOIN #rust", user_id);
+        }
+        send("PRIVMSG #rust :From alice", alice);
+        send("PRIVMSG #rust :From dave", dave);
+        send("PRIVMSG #rust :From alice again", alice);
+        let msgids = state.history.lock().unwrap().targets["#rust"]
+            .iter()
+            .map(|message| message.tags["msgid"].clone())
+            .collect::<Vec<_>>();
+        let texts = || {
+            texts(
+                state
+                    .history
+                    .lock()
+                    .unwrap()
+                    .query("#rust", &Query::Latest(None), 10),
+            )
+        };
+
+        // Neither the author nor an operator
+        send(&format!("REDACT #rust {} :Not yours", msgids[0]), carol);
+        assert_eq!(texts(), ["From alice", "From dave", "From alice again"]);
+
+        // Authors are matched by account if they were logged in, and by prefix if not
+        send(&format!("REDACT #rust {}", msgids[0]), alice);
+        send(&format!("REDACT #rust {}", msgids[1]), dave);
+        assert_eq!(texts(), ["From alice again"]);
+        send(&format!("REDACT #rust {} :Spam", msgids[2]), bob);
+        assert!(texts().is_empty());
+
+        let replies = state::disconnect(&state, carol, carol_client);
+        assert!(replies.contains(&format!(
+            ":127.0.0.1 FAIL REDACT REDACT_FORBIDDEN #rust {} :You can't redact that message.",
+            msgids[0]
+        )));
+        let redactions = state::disconnect(&state, alice, alice_client)
+            .into_iter()
+            .filter(|line| line.contains(" REDACT #rust "))
+            .count();
+        assert_eq!(redactions, 3);
+        state::disconnect(&state, bob, bob_client);
+        state::disconnect(&state, dave, dave_client);
+    }
+}
@@ -1,33 +1,244 @@
 // #![allow(unused)]
-#![feature(result_option_inspect)]
 
-mod message;
+mod accounts;
+mod bans;
+mod caps;
+mod channel_store;
+mod channels;
+mod config;
+mod error;
+mod flood;
+mod handler;
+mod history;
+mod mask;
+mod modes;
+mod monitor;
+mod multiline;
+mod oper;
+mod query;
+mod registration;
+mod sasl;
 mod server;
+mod state;
+mod store;
 mod user;
 
+use accounts::AccountStore;
+use bans::BanList;
+use channel_store::ChannelStore;
+use config::Config;
+use history::History;
+use shared::message::{Command, Message, ToIrc};
+use signal_hook::{consts::SIGTERM, iterator::Signals};
+use state::{ServerState, ShutdownMode};
 use std::{
-    collections::HashMap,
+    env,
+    io::{ErrorKind, Write},
     net::TcpListener,
-    sync::{Arc, Mutex},
-    thread,
+    os::unix::process::CommandExt,
+    process,
+    sync::{Arc, PoisonError},
+    thread::{self, JoinHandle},
+    time::Duration,
 };
-use user::{Channel, User};
-use uuid::Uuid;
+
+/// How often listeners check for new connections.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the server waits for connections to close when shutting down.
+const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
 
 fn main() {
-    let port = 8080;
-    let hostname = &format!("127.0.0.1:{port}"); // TODO: Allow for custom port
-    let listener = TcpListener::bind(hostname).expect(&format!("Couldn't bind to {hostname}."));
-    println!("Listening on {hostname}.");
+    let args = env::args().collect::<Vec<_>>();
+
+    // Print the hash of a password to put in an operator block or account
+    if args.get(1).map(String::as_str) == Some("--mkpasswd") {
+        let password = args.get(2).unwrap_or_else(|| {
+            println!("Usage: server --mkpasswd <password>");
+            process::exit(1);
+        });
+        println!("{}", bcrypt::hash(password, bcrypt::DEFAULT_COST).unwrap());
+        return;
+    }
+
+    let config_path = args
+        .get(1)
+        .cloned()
+        .unwrap_or_else(|| "server.toml".to_string());
+    let config = Config::load(&config_path).unwrap_or_else(|err| {
+        println!("Couldn't load configuration from {config_path}: {err}");
+        process::exit(1);
+    });
+
+    let bans = BanList::load(&config.bans_file).unwrap_or_else(|err| {
+        println!("Couldn't load bans from {}: {err}", config.bans_file);
+        process::exit(1);
+    });
+
+    let accounts = AccountStore::load(&config.accounts_file).unwrap_or_else(|err| {
+        println!(
+            "Couldn't load accounts from {}: {err}",
+            config.accounts_file
+        );
+        process::exit(1);
+    });
+
+    let channel_store = ChannelStore::load(&config.channels_file).unwrap_or_else(|err| {
+        println!(
+            "Couldn't load channels from {}: {err}",
+            config.channels_file
+        );
+        process::exit(1);
+    });
+
+    let history = History::load(config.history_file.as_deref(), config.history_length)
+        .unwrap_or_else(|err| {
+            println!("Couldn't load message history: {err}");
+            process::exit(1);
+        });
+
+    let listeners = config.listeners.clone();
+    let state = Arc::new(ServerState::new(
+        config,
+        &config_path,
+        bans,
+        accounts,
+        channel_store,
+        history,
+    ));
+
+    // Take registered nicknames back from users who don't log in to them in time
+    let enforcer_state = state.clone();
+    thread::spawn(move || registration::enforce_nicknames(enforcer_state));
+
+    // Write new messages to the history file every so often rather than after each one
+    let history_state = state.clone();
+    thread::spawn(move || History::save_periodically(history_state));
+
+    // SIGTERM shuts the server down the same way DIE does
+    let mut signals = Signals::new([SIGTERM]).unwrap_or_else(|err| {
+        println!("Couldn't listen for signals: {err}");
+        process::exit(1);
+    });
+    let signal_state = state.clone();
+    thread::spawn(move || {
+        if signals.forever().next().is_some() {
+            signal_state.request_shutdown(ShutdownMode::Exit);
+        }
+    });
+
+    // Accept connections on every configured address in its own thread
+    let handles = listeners
+        .into_iter()
+        .map(|config| {
+            let address = config.address;
+            let listener = TcpListener::bind(&address)
+                .unwrap_or_else(|_| panic!("Couldn't bind to {address}."));
+            println!("Listening on {address}.");
 
-    let users = Arc::new(Mutex::new(HashMap::<Uuid, User>::new()));
-    let channels = Arc::new(Mutex::new(HashMap::<String, Arc<Channel>>::new()));
+            // The listener doesn't block so that it can stop accepting connections once the server
+            // is shutting down
+            listener
+                .set_nonblocking(true)
+                .expect("Failed to make the listener non-blocking.");
+            let state = state.clone();
+            thread::spawn(move || {
+                while !state.is_shutting_down() {
+                    let mut stream = match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                            thread::sleep(ACCEPT_INTERVAL);
+                            continue;
+                        }
+                        Err(err) => {
+                            println!("Failed to accept a connection on {address}: {err}");
+                            continue;
+                        }
+                    };
+                    if stream.set_nonblocking(false).is_err() {
+                        continue;
+                    }
+                    let peer = match stream.peer_addr() {
+                        Ok(peer) => peer.ip(),
+                        Err(_) => continue,
+                    };
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
-        let users = users.clone();
-        let channels = channels.clone();
+                    // Turn the connection away before it gets a user if it's over the limits of
+                    // its class
+                    let slot = match state.admit(peer) {
+                        Ok(slot) => slot,
+                        Err(reason) => {
+                            let error = Message::new(
+                                Some(state.server_name()),
+                                Command::Error,
+                                &[&format!("Closing link: {reason}")],
+                            );
+                            let _ = stream.write_all(error.to_irc().as_bytes());
+                            continue;
+                        }
+                    };
+
+                    let state = state.clone();
+                    let address = address.clone();
+                    thread::spawn(move || {
+                        // The connection stops counting against the limits once its thread ends,
+                        // even if it panicked
+                        let _slot = slot;
+                        server::handle_connection(stream, state, &address)
+                    });
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mode = state.wait_for_shutdown();
+    shut_down(&state, handles);
+
+    if mode == ShutdownMode::Restart {
+        // `exec` only returns if starting the new process failed
+        let err = process::Command::new(env::current_exe().unwrap())
+            .args(env::args().skip(1))
+            .exec();
+        println!("Couldn't restart the server: {err}");
+        process::exit(1);
+    }
+}
+
+/// Stop accepting connections, tell everyone the server is going away, and save what needs to
+/// survive a restart. Connections still open after `SHUTDOWN_DEADLINE` are left to be closed when
+/// the process exits.
+fn shut_down(state: &ServerState, listeners: Vec<JoinHandle<()>>) {
+    println!("Shutting down.");
+    for handle in listeners {
+        handle.join().expect("Listener thread panicked.");
+    }
+
+    // Dropping the users closes their connections once the ERROR has been written, which ends
+    // their connection threads
+    let error = Message::new(
+        Some(state.server_name()),
+        Command::Error,
+        &["Server shutting down"],
+    );
+    // A connection thread that panicked may have left the table poisoned, but everyone still has
+    // to be told
+    let users = state
+        .users
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .drain()
+        .collect::<Vec<_>>();
+    for (_, mut user) in users {
+        let _ = user.send(&error);
+    }
+
+    if let Err(err) = state.save_channels() {
+        println!("Couldn't save channels: {err}");
+    }
+    if let Err(err) = History::save(&state.history) {
+        println!("Couldn't save message history: {err}");
+    }
 
-        thread::spawn(move || server::handle_connection(stream, users, channels, "127.0.0.1"));
+    if !state.wait_for_connections(SHUTDOWN_DEADLINE) {
+        println!("Some connections didn't close in time.");
     }
 }
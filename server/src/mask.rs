@@ -0,0 +1,107 @@
+use std::net::IpAddr;
+
+/// Check whether `text` matches a wildcard `mask`, where `*` matches any number of characters and
+/// `?` matches exactly one. Matching is case-insensitive, as nicknames and hostnames are.
+pub fn matches(mask: &str, text: &str) -> bool {
+    let mask = mask.to_lowercase().chars().collect::<Vec<_>>();
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+
+    let (mut m, mut t) = (0, 0);
+    // Position of the last `*` in the mask and the text position it was tried against
+    let mut backtrack = None;
+
+    while t < text.len() {
+        if m < mask.len() && (mask[m] == '?' || mask[m] == text[t]) {
+            m += 1;
+            t += 1;
+        } else if m < mask.len() && mask[m] == '*' {
+            backtrack = Some((m, t));
+            m += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last `*` swallow one more character and try again
+            m = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+
+    mask[m..].iter().all(|&c| c == '*')
+}
+
+/// Check whether `address` is inside `network`, given either as a single address or in CIDR
+/// notation (e.g. `192.168.0.0/16`). Anything else is treated as a wildcard mask.
+pub fn matches_network(network: &str, address: IpAddr) -> bool {
+    let (base, prefix) = match network.split_once('/') {
+        Some((base, prefix)) => (base, prefix.parse::<u32>().ok()),
+        None => (network, None),
+    };
+    let base = match base.parse::<IpAddr>() {
+        Ok(base) => base,
+        Err(_) => return matches(network, &address.to_string()),
+    };
+
+    match (base, address) {
+        (IpAddr::V4(base), IpAddr::V4(address)) => {
+            let prefix = prefix.unwrap_or(32).min(32);
+            let netmask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+            u32::from(base) & netmask == u32::from(address) & netmask
+        }
+        (IpAddr::V6(base), IpAddr::V6(address)) => {
+            let prefix = prefix.unwrap_or(128).min(128);
+            let netmask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+            u128::from(base) & netmask == u128::from(address) & netmask
+        }
+        _ => false,
+    }
+}
+
+/// Check whether a ban mask would match everyone: a `user@host` mask or address made of nothing
+/// but wildcards, or a network with a /0 prefix.
+pub fn matches_everything(mask: &str) -> bool {
+    if let Some((_, prefix)) = mask.split_once('/') {
+        if prefix.parse::<u32>() == Ok(0) {
+            return true;
+        }
+    }
+    mask.split('@')
+        .all(|part| part.chars().all(|c| c == '*' || c == '?'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcard_masks() {
+        assert!(matches("*@127.0.0.1", "guest@127.0.0.1"));
+        assert!(matches("g?est@*", "GUEST@example.com"));
+        assert!(matches("*spam*", "nospamplease"));
+        assert!(!matches("*@10.*", "guest@127.0.0.1"));
+        assert!(!matches("guest", "guests"));
+    }
+
+    #[test]
+    fn network_masks() {
+        let address = "192.168.1.20".parse().unwrap();
+        assert!(matches_network("192.168.0.0/16", address));
+        assert!(matches_network("192.168.1.20", address));
+        assert!(matches_network("0.0.0.0/0", address));
+        assert!(matches_network("192.168.1.*", address));
+        assert!(!matches_network("192.168.2.0/24", address));
+        assert!(!matches_network("::/0", address));
+    }
+
+    #[test]
+    fn masks_matching_everything() {
+        assert!(matches_everything("*@*"));
+        assert!(matches_everything("?*@**"));
+        assert!(matches_everything("*"));
+        assert!(matches_everything("0.0.0.0/0"));
+        assert!(matches_everything("::/0"));
+        assert!(!matches_everything("*@10.*"));
+        assert!(!matches_everything("guest@*"));
+        assert!(!matches_everything("10.0.0.0/8"));
+    }
+}
@@ -0,0 +1,361 @@
+use crate::{
+    error::ServerError,
+    server::{nickname_in_use, send_to_channel, send_to_user, CommandResponse},
+    state::ServerState,
+};
+use shared::message::{Command, Message, ReplyCode, Response};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub fn handle_mode(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: MODE alice +iw
+    //          MODE #channel
+    let target = message.params[0].clone();
+
+    if target.starts_with('#') {
+        return handle_channel_mode(message, state, user_id, &target);
+    }
+
+    let mut lock = users.lock()?;
+    let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+    let nickname = user.nickname.clone().unwrap();
+
+    // Users can only view and change their own modes
+    if target != nickname {
+        drop(lock);
+        let response = if nickname_in_use(&target, users)? {
+            Response::new(
+                server_prefix,
+                ReplyCode::ERR_USERSDONTMATCH,
+                &["Cannot change mode for other users."],
+            )
+        } else {
+            Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOSUCHNICK,
+                &["The given nick was not found."],
+            )
+        };
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let modestring = match message.params.get(1) {
+        Some(modestring) => modestring,
+        None => {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::RPL_UMODEIS,
+                &[&nickname, &user.mode_string()],
+            );
+            drop(lock);
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    // Apply the modes one by one, keeping track of the ones that actually changed
+    let mut changes = ModeChanges::default();
+    let mut unknown_flag = false;
+    let mut adding = true;
+    for flag in modestring.chars() {
+        match flag {
+            '+' => adding = true,
+            '-' => adding = false,
+            'i' | 'w' => {
+                let mode = if flag == 'i' {
+                    &mut user.modes.invisible
+                } else {
+                    &mut user.modes.wallops
+                };
+                if *mode != adding {
+                    *mode = adding;
+                    changes.push(adding, flag);
+                }
+            }
+            // Operator status can only be given up. It's gained with OPER.
+            'o' => {
+                if !adding && user.operator.is_some() {
+                    user.operator = None;
+                    changes.push(adding, flag);
+                }
+            }
+            // Only the server sets +r when the user logs in to an account
+            'r' => {}
+            _ => unknown_flag = true,
+        }
+    }
+    let prefix = user.prefix();
+    drop(lock);
+
+    if unknown_flag {
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::ERR_UMODEUNKNOWNFLAG,
+            &["Unknown MODE flag."],
+        );
+        send_to_user(&response, users, user_id)?;
+    }
+
+    if !changes.is_empty() {
+        let mode = Message::new(prefix, Command::Mode, &[&nickname, &changes.to_string()]);
+        send_to_user(&mode, users, user_id)?;
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+fn handle_channel_mode(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+    target: &str,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: MODE #rust +kl hunter2 10
+    //          MODE #rust -i+o bob
+    let channel = state.channels.lock().unwrap().get(target).cloned();
+    let channel = match channel {
+        Some(channel) => channel,
+        None => {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOSUCHCHANNEL,
+                &["The given channel was not found."],
+            );
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    let modestring = match message.params.get(1) {
+        Some(modestring) => modestring,
+        None => {
+            let mut params = vec![target.to_string()];
+            params.extend(channel.modes.lock().unwrap().to_params());
+            let params = params.iter().map(String::as_str).collect::<Vec<_>>();
+            let response = Response::new(server_prefix, ReplyCode::RPL_CHANNELMODEIS, &params);
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    // Anyone can list the channel's bans, not just operators
+    if message.params.len() == 2 && matches!(modestring.as_str(), "b" | "+b") {
+        let bans = channel.bans.lock().unwrap().clone();
+        for mask in bans {
+            let response = Response::new(server_prefix, ReplyCode::RPL_BANLIST, &[target, &mask]);
+            send_to_user(&response, users, user_id)?;
+        }
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::RPL_ENDOFBANLIST,
+            &[target, "End of channel ban list."],
+        );
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    if !channel.is_operator(user_id) {
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::ERR_CHANOPRIVSNEEDED,
+            &[target, "You're not channel operator."],
+        );
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    // Look up the members up front so operator status can be given by nickname
+    let members = users
+        .lock()?
+        .values()
+        .filter(|user| user.channel.as_ref() == Some(&channel))
+        .map(|user| (user.nickname.clone().unwrap(), user.id))
+        .collect::<HashMap<_, _>>();
+
+    let mut changes = ModeChanges::default();
+    let mut errors = vec![];
+    let mut arguments = message.params.iter().skip(2);
+    let mut adding = true;
+    {
+        let mut modes = channel.modes.lock().unwrap();
+        for flag in modestring.chars() {
+            match flag {
+                '+' => adding = true,
+                '-' => adding = false,
+                'i' => {
+                    if modes.invite_only != adding {
+                        modes.invite_only = adding;
+                        changes.push(adding, flag);
+                    }
+                }
+                't' => {
+                    if modes.topic_lock != adding {
+                        modes.topic_lock = adding;
+                        changes.push(adding, flag);
+                    }
+                }
+                'b' => {
+                    let mask = match arguments.next() {
+                        Some(mask) => mask,
+                        None => continue,
+                    };
+                    let mut bans = channel.bans.lock().unwrap();
+                    let exists = bans.iter().any(|ban| ban.eq_ignore_ascii_case(mask));
+                    if adding && !exists {
+                        bans.push(mask.clone());
+                        changes.push_with_param(adding, flag, mask);
+                    } else if !adding && exists {
+                        bans.retain(|ban| !ban.eq_ignore_ascii_case(mask));
+                        changes.push_with_param(adding, flag, mask);
+                    }
+                }
+                'k' => {
+                    // The key is also given when removing it, but it doesn't have to match
+                    let key = arguments.next();
+                    if adding {
+                        if let Some(key) = key {
+                            modes.key = Some(key.clone());
+                            changes.push_with_param(adding, flag, key);
+                        }
+                    } else if modes.key.take().is_some() {
+                        changes.push(adding, flag);
+                    }
+                }
+                'l' => {
+                    if adding {
+                        if let Some(limit) = arguments.next().and_then(|limit| limit.parse().ok()) {
+                            modes.limit = Some(limit);
+                            changes.push_with_param(adding, flag, &limit.to_string());
+                        }
+                    } else if modes.limit.take().is_some() {
+                        changes.push(adding, flag);
+                    }
+                }
+                'o' => {
+                    let nickname = match arguments.next() {
+                        Some(nickname) => nickname,
+                        None => continue,
+                    };
+                    match members.get(nickname) {
+                        Some(&member_id) => {
+                            let mut operators = channel.operators.lock().unwrap();
+                            let changed = if adding {
+                                operators.insert(member_id)
+                            } else {
+                                operators.remove(&member_id)
+                            };
+                            if changed {
+                                changes.push_with_param(adding, flag, nickname);
+                            }
+                        }
+                        None => errors.push(Response::new(
+                            server_prefix,
+                            ReplyCode::ERR_USERNOTINCHANNEL,
+                            &[nickname, target, "They aren't on that channel."],
+                        )),
+                    }
+                }
+                _ => errors.push(Response::new(
+                    server_prefix,
+                    ReplyCode::ERR_UNKNOWNMODE,
+                    &[&flag.to_string(), "is unknown mode char to me."],
+                )),
+            }
+        }
+    }
+
+    for response in errors {
+        send_to_user(&response, users, user_id)?;
+    }
+
+    // Tell everyone in the channel about the changes
+    if !changes.is_empty() {
+        let mut params = vec![target.to_string(), changes.to_string()];
+        params.extend(changes.params);
+        let params = params.iter().map(String::as_str).collect::<Vec<_>>();
+        let mode = Message::new(message.prefix, Command::Mode, &params);
+        send_to_channel(&mode, users, &channel)?;
+
+        if channel.is_registered() {
+            state.save_channels()?;
+        }
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+/// A mode string built up from individual changes, e.g. `+i-w`, along with the parameters of the
+/// modes that take one.
+#[derive(Default)]
+struct ModeChanges {
+    modes: String,
+    params: Vec<String>,
+    adding: Option<bool>,
+}
+
+impl ModeChanges {
+    fn push(&mut self, adding: bool, flag: char) {
+        // Only write the sign when it differs from the previous change
+        if self.adding != Some(adding) {
+            self.modes.push(if adding { '+' } else { '-' });
+            self.adding = Some(adding);
+        }
+        self.modes.push(flag);
+    }
+
+    fn push_with_param(&mut self, adding: bool, flag: char, param: &str) {
+        self.push(adding, flag);
+        self.params.push(param.to_string());
+    }
+
+    fn is_empty(&self) -> bool {
+        self.modes.is_empty()
+    }
+}
+
+impl std::fmt::Display for ModeChanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.modes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, state::tests as state};
+
+    #[test]
+    fn invisible_mode_is_applied_and_echoed() {
+        let state = state::server(Config::default());
+        let (user_id, client) = state::register(&state, "alice");
+        let mode = |line: &str| {
+            let message = Message::from(line).unwrap();
+            state.handlers.dispatch(message, &state, user_id).unwrap();
+            state.users.lock().unwrap()[&user_id].modes.invisible
+        };
+
+        assert!(mode("MODE alice +i"));
+        assert!(mode("MODE alice +i"));
+        assert!(!mode("MODE alice -i"));
+        assert!(!mode("MODE alice -i"));
+
+        assert_eq!(
+            state::disconnect(&state, user_id, client),
+            [
+                ":alice!alice@127.0.0.1 MODE alice +i",
+                ":alice!alice@127.0.0.1 MODE alice -i",
+            ]
+        );
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{
+    error::ServerError,
+    server::{send_to_user, CommandResponse},
+    state::ServerState,
+};
+use shared::message::{join_list, Message, ReplyCode, Response};
+use uuid::Uuid;
+
+pub fn handle_monitor(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: MONITOR + alice,bob
+    //          MONITOR - bob
+    //          MONITOR C
+    //          MONITOR L
+    //          MONITOR S
+    let subcommand = message.params[0].to_uppercase();
+    let targets = message
+        .params
+        .get(1)
+        .map(|targets| {
+            targets
+                .split(',')
+                .filter(|target| !target.is_empty())
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    if matches!(subcommand.as_str(), "+" | "-") && targets.is_empty() {
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::ERR_NEEDMOREPARAMS,
+            &["MONITOR", "Not enough parameters."],
+        );
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let limit = state.config.read().unwrap().monitor_limit;
+    let mut lock = users.lock()?;
+    let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+    let nickname = user.nickname.clone().unwrap();
+
+    let (added, full) = match subcommand.as_str() {
+        "+" => {
+            let mut added = vec![];
+            let mut full = vec![];
+            for target in targets {
+                let is_monitored = user
+                    .monitoring
+                    .iter()
+                    .any(|nickname| nickname.eq_ignore_ascii_case(&target));
+                if is_monitored {
+                    continue;
+                }
+                if user.monitoring.len() >= limit {
+                    full.push(target);
+                } else {
+                    user.monitoring.push(target.clone());
+                    added.push(target);
+                }
+            }
+            (added, full)
+        }
+        "-" => {
+            user.monitoring.retain(|nickname| {
+                !targets
+                    .iter()
+                    .any(|target| target.eq_ignore_ascii_case(nickname))
+            });
+            return Ok(CommandResponse::Continue);
+        }
+        "C" => {
+            user.monitoring.clear();
+            return Ok(CommandResponse::Continue);
+        }
+        "L" => {
+            let monitoring = user.monitoring.clone();
+            drop(lock);
+            send_list(state, user_id, ReplyCode::RPL_MONLIST, monitoring)?;
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::RPL_ENDOFMONLIST,
+                &[&nickname, "End of MONITOR list"],
+            );
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+        "S" => (user.monitoring.clone(), vec![]),
+        _ => {
+            drop(lock);
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_UNKNOWNCOMMAND,
+                &["Unknown command."],
+            );
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    // Tell the user which of the nicknames are online right away
+    let mut online = vec![];
+    let mut offline = vec![];
+    for target in added {
+        let prefix = lock
+            .values()
+            .filter(|user| user.is_registered)
+            .find(|user| {
+                user.nickname
+                    .as_ref()
+                    .is_some_and(|nickname| nickname.eq_ignore_ascii_case(&target))
+            })
+            .and_then(|user| user.prefix());
+        match prefix {
+            Some(prefix) => online.push(prefix),
+            None => offline.push(target),
+        }
+    }
+    drop(lock);
+
+    send_list(state, user_id, ReplyCode::RPL_MONONLINE, online)?;
+    send_list(state, user_id, ReplyCode::RPL_MONOFFLINE, offline)?;
+    if !full.is_empty() {
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::ERR_MONLISTFULL,
+            &[
+                &nickname,
+                &limit.to_string(),
+                &full.join(","),
+                "Monitor list is full.",
+            ],
+        );
+        send_to_user(&response, users, user_id)?;
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Tell the users monitoring a nickname that someone is now using it. `prefix` is their full
+/// `nick!user@host`.
+pub fn notify_online(state: &ServerState, prefix: &str) -> Result<(), ServerError> {
+    let nickname = prefix.split('!').next().unwrap_or(prefix);
+    notify(state, nickname, ReplyCode::RPL_MONONLINE, prefix)
+}
+
+/// Tell the users monitoring a nickname that nobody is using it anymore.
+pub fn notify_offline(state: &ServerState, nickname: &str) -> Result<(), ServerError> {
+    notify(state, nickname, ReplyCode::RPL_MONOFFLINE, nickname)
+}
+
+fn notify(
+    state: &ServerState,
+    nickname: &str,
+    code: ReplyCode,
+    text: &str,
+) -> Result<(), ServerError> {
+    let server_prefix = &state.server_name();
+
+    let mut lock = state.users.lock()?;
+    let watchers = lock.values_mut().filter(|user| {
+        user.is_registered
+            && user
+                .monitoring
+                .iter()
+                .any(|target| target.eq_ignore_ascii_case(nickname))
+    });
+    for user in watchers {
+        let target = user.nickname.clone().unwrap();
+        user.send(&Response::new(server_prefix, code, &[&target, text]))?;
+    }
+
+    Ok(())
+}
+
+/// Send a list of nicknames with a MONITOR reply, split over several replies if it's long.
+fn send_list(
+    state: &ServerState,
+    user_id: Uuid,
+    code: ReplyCode,
+    items: Vec<String>,
+) -> Result<(), ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+    let nickname = users
+        .lock()?
+        .get(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .nickname
+        .clone()
+        .unwrap();
+
+    for line in join_list(&items, ',') {
+        let response = Response::new(server_prefix, code, &[&nickname, &line]);
+        send_to_user(&response, users, user_id)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, state::tests as state};
+
+    #[test]
+    fn reports_monitored_nicknames() {
+        let state = state::server(Config {
+            monitor_limit: 2,
+            ..Config::default()
+        });
+        let (alice, client) = state::register(&state, "alice");
+        let (_bob, _bob_client) = state::register(&state, "bob");
+        let monitor = |line: &str| {
+            let message = Message::from(line).unwrap();
+            state.handlers.dispatch(message, &state, alice).unwrap();
+        };
+
+        monitor("MONITOR + Bob,carol,dave");
+        notify_offline(&state, "bob").unwrap();
+        monitor("MONITOR - carol");
+        monitor("MONITOR L");
+
+        let replies = state::disconnect(&state, alice, client);
+        assert_eq!(
+            replies,
+            [
+                ":127.0.0.1 730 alice bob!bob@127.0.0.1",
+                ":127.0.0.1 731 alice carol",
+                ":127.0.0.1 734 alice 2 dave :Monitor list is full.",
+                ":127.0.0.1 731 alice bob",
+                ":127.0.0.1 732 alice Bob",
+                ":127.0.0.1 733 alice :End of MONITOR list",
+            ]
+        );
+    }
+}
@@ -0,0 +1,428 @@
+use crate::{
+    error::ServerError,
+    history,
+    server::{get_nickname_id, send_to_user, stamp_message, CommandResponse},
+    state::ServerState,
+};
+use shared::message::{Batch, Command, Message, ReplyCode, Response, Tags};
+use std::mem;
+use uuid::Uuid;
+
+/// Tag a client puts on a line of a multi-line message to join it to the previous line without a
+/// line break.
+const CONCAT_TAG: &str = "draft/multiline-concat";
+
+/// A multi-line message being received, held until its batch ends.
+#[derive(Debug)]
+pub struct PendingMultiline {
+    batch: Batch,
+    /// Tags the client sent with the start of the batch
+    tags: Tags,
+    lines: Vec<Message>,
+    /// Bytes of text in the lines so far, counting the line breaks between them
+    bytes: usize,
+    /// Error rejecting the message once the batch ends, if a line broke a rule
+    error: Option<Message>,
+}
+
+pub fn handle_batch(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: BATCH +abc draft/multiline #rust
+    //          @batch=abc PRIVMSG #rust :first line
+    //          @batch=abc PRIVMSG #rust :second line
+    //          BATCH -abc
+    let reference = message.params[0].clone();
+
+    if let Some(reference) = reference.strip_prefix('-') {
+        let pending = users
+            .lock()?
+            .get_mut(&user_id)
+            .ok_or(ServerError::NoSuchUser)?
+            .multiline
+            .take();
+        return match pending {
+            Some(pending) if pending.batch.reference == reference => {
+                deliver(state, user_id, pending)
+            }
+            _ => {
+                let error = fail(
+                    server_prefix,
+                    &["MULTILINE_INVALID", "That batch isn't open."],
+                );
+                send_to_user(&error, users, user_id)?;
+                Ok(CommandResponse::Continue)
+            }
+        };
+    }
+
+    // Multi-line messages are the only batches clients can send
+    let batch = Batch::from_start(&message).filter(|batch| {
+        batch.kind == "draft/multiline" && batch.params.len() == 1 && !batch.reference.is_empty()
+    });
+    let mut lock = users.lock()?;
+    let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+    let error = match batch {
+        _ if !user.capabilities.contains("draft/multiline") => {
+            Some("You need the draft/multiline capability to send multi-line messages.")
+        }
+        _ if user.multiline.is_some() => Some("Multi-line messages can't be nested."),
+        None => Some("Only draft/multiline batches with a target are supported."),
+        Some(batch) => {
+            user.multiline = Some(PendingMultiline {
+                batch,
+                tags: message.tags,
+                lines: vec![],
+                bytes: 0,
+                error: None,
+            });
+            None
+        }
+    };
+    drop(lock);
+
+    if let Some(text) = error {
+        send_to_user(
+            &fail(server_prefix, &["MULTILINE_INVALID", text]),
+            users,
+            user_id,
+        )?;
+    }
+    Ok(CommandResponse::Continue)
+}
+
+/// Hold a line of a multi-line message until its batch ends. `concat` is whether the line joins
+/// the previous one without a line break.
+pub fn add_line(
+    mut message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+    reference: &str,
+    concat: bool,
+) -> Result<CommandResponse, ServerError> {
+    let server_prefix = &state.server_name();
+    let (max_lines, max_bytes) = {
+        let config = state.config.read().unwrap();
+        (config.multiline_max_lines, config.multiline_max_bytes)
+    };
+
+    let is_open = {
+        let mut lock = state.users.lock()?;
+        let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+        match &mut user.multiline {
+            Some(pending) if pending.batch.reference == reference => {
+                // Every line has to be the same kind of message to the batch's target
+                let is_valid = matches!(message.command, Command::PrivMsg | Command::Notice)
+                    && message.params.len() == 2
+                    && message.params[0] == pending.batch.params[0]
+                    && pending.lines.first().is_none_or(|first| {
+                        mem::discriminant(&first.command) == mem::discriminant(&message.command)
+                    })
+                    && !(concat && pending.lines.is_empty());
+
+                let text = message.params.get(1).map_or("", String::as_str);
+                let bytes =
+                    pending.bytes + text.len() + usize::from(!concat && !pending.lines.is_empty());
+                if pending.error.is_some() {
+                    // The message is already rejected, so there's no point in keeping its lines
+                } else if !is_valid {
+                    pending.error = Some(fail(
+                        server_prefix,
+                        &[
+                            "MULTILINE_INVALID",
+                            "Lines have to be messages of the same kind to the batch's target.",
+                        ],
+                    ));
+                } else if pending.lines.len() >= max_lines {
+                    pending.error = Some(fail(
+                        server_prefix,
+                        &[
+                            "MULTILINE_MAX_LINES",
+                            &max_lines.to_string(),
+                            "The multi-line message has too many lines.",
+                        ],
+                    ));
+                } else if bytes > max_bytes {
+                    pending.error = Some(fail(
+                        server_prefix,
+                        &[
+                            "MULTILINE_MAX_BYTES",
+                            &max_bytes.to_string(),
+                            "The multi-line message is too long.",
+                        ],
+                    ));
+                } else {
+                    if concat {
+                        message.tags.insert(CONCAT_TAG.to_string(), String::new());
+                    }
+                    pending.lines.push(message);
+                    pending.bytes = bytes;
+                }
+                true
+            }
+            _ => false,
+        }
+    };
+
+    if !is_open {
+        let error = fail(
+            server_prefix,
+            &[
+                "MULTILINE_INVALID",
+                "That message is tagged with a batch that isn't open.",
+            ],
+        );
+        send_to_user(&error, &state.users, user_id)?;
+    }
+    Ok(CommandResponse::Continue)
+}
+
+/// Relay a multi-line message once its batch has ended. Recipients that negotiated
+/// `draft/multiline` get it as a batch, while everyone else gets each line as its own message.
+fn deliver(
+    state: &ServerState,
+    user_id: Uuid,
+    pending: PendingMultiline,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    let error = match pending.error {
+        Some(error) => Some(error),
+        None if pending.lines.is_empty() => Some(fail(
+            server_prefix,
+            &["MULTILINE_INVALID", "The batch has no lines."],
+        )),
+        None => None,
+    };
+    if let Some(error) = error {
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let target = pending.batch.params[0].clone();
+    let is_notice = matches!(pending.lines[0].command, Command::Notice);
+    let prefix = pending.lines[0].prefix.clone();
+
+    // Find who the message goes to, the same way as for a single PRIVMSG or NOTICE
+    let (recipients, key) = if target.starts_with('#') {
+        let channel = state.channels.lock().unwrap().get(&target).cloned();
+        match channel {
+            Some(channel) => {
+                let members = users
+                    .lock()?
+                    .values()
+                    .filter(|user| user.id != user_id && user.channel.as_ref() == Some(&channel))
+                    .map(|user| user.id)
+                    .collect::<Vec<_>>();
+                (members, Some(history::channel_key(&channel.name)))
+            }
+            None => {
+                if !is_notice {
+                    let response = Response::new(
+                        server_prefix,
+                        ReplyCode::ERR_NOSUCHCHANNEL,
+                        &["The given channel was not found."],
+                    );
+                    send_to_user(&response, users, user_id)?;
+                }
+                return Ok(CommandResponse::Continue);
+            }
+        }
+    } else {
+        match get_nickname_id(&target, users)? {
+            Some(recipient_id) => {
+                let key = history::users_direct_key(users, user_id, recipient_id)?;
+                (vec![recipient_id], key)
+            }
+            None => {
+                if !is_notice {
+                    let response = Response::new(
+                        server_prefix,
+                        ReplyCode::ERR_NOSUCHNICK,
+                        &["The given nick was not found."],
+                    );
+                    send_to_user(&response, users, user_id)?;
+                }
+                return Ok(CommandResponse::Continue);
+            }
+        }
+    };
+
+    // The batch is relayed under a reference of the server's own, stamped as a whole
+    let relay = Batch::new("draft/multiline", &[&target]);
+    let mut batch = relay.wrap(prefix.clone(), pending.lines.clone());
+    batch[0].tags.extend(pending.tags);
+    stamp_message(&mut batch[0]);
+
+    // Lines joined with `draft/multiline-concat` become one line again, and empty lines are
+    // dropped since they can't be sent on their own
+    let mut fallback: Vec<Message> = vec![];
+    for mut line in pending.lines {
+        let joins_previous = line.tags.remove(CONCAT_TAG).is_some();
+        match fallback.last_mut() {
+            Some(previous) if joins_previous => previous.params[1].push_str(&line.params[1]),
+            _ => fallback.push(line),
+        }
+    }
+    fallback.retain(|line| !line.params[1].is_empty());
+
+    // The lines share the batch's time and msgid, so that the message has the same ID however it
+    // was received and can be found in history by it
+    for line in &mut fallback {
+        for tag in ["time", "msgid"] {
+            line.tags
+                .insert(tag.to_string(), batch[0].tags[tag].clone());
+        }
+    }
+
+    {
+        let mut lock = users.lock()?;
+        for (id, user) in lock.iter_mut() {
+            let is_recipient = recipients.contains(id)
+                || (*id == user_id && user.capabilities.contains("echo-message"));
+            if !is_recipient {
+                continue;
+            }
+
+            let messages = if user.capabilities.contains("draft/multiline")
+                && user.capabilities.contains("batch")
+            {
+                &batch
+            } else {
+                &fallback
+            };
+            for message in messages {
+                user.send(message)?;
+            }
+        }
+    }
+
+    if let Some(key) = key {
+        let mut history = state.history.lock().unwrap();
+        for line in fallback {
+            history.add(&key, line);
+        }
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Return the error rejecting a multi-line message, e.g. `FAIL BATCH MULTILINE_MAX_LINES 100 :...`.
+fn fail(server_prefix: &str, params: &[&str]) -> Message {
+    let mut params = params.to_vec();
+    params.insert(0, "BATCH");
+    Message::new(Some(server_prefix.to_string()), Command::Fail, &params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, state::tests as state};
+
+    /// Send a multi-line message to `target` made of `lines`, each with `+` in front of it if it
+    /// joins the previous line.
+    fn send(state: &ServerState, user_id: Uuid, target: &str, lines: &[&str]) {
+        let start = Message::from(&format!("BATCH +ml draft/multiline {target}")).unwrap();
+        state.handlers.dispatch(start, state, user_id).unwrap();
+        for line in lines {
+            let (text, concat) = match line.strip_prefix('+') {
+                Some(text) => (text, true),
+                None => (*line, false),
+            };
+            let message = Message::new(None, Command::PrivMsg, &[target, text]);
+            add_line(message, state, user_id, "ml", concat).unwrap();
+        }
+        let end = Message::from("BATCH -ml").unwrap();
+        state.handlers.dispatch(end, state, user_id).unwrap();
+    }
+
+    fn enable_multiline(state: &ServerState, user_id: Uuid) {
+        let mut users = state.users.lock().unwrap();
+        let capabilities = &mut users.get_mut(&user_id).unwrap().capabilities;
+        capabilities.insert("batch".to_string());
+        capabilities.insert("draft/multiline".to_string());
+        capabilities.insert("message-tags".to_string());
+    }
+
+    #[test]
+    fn falls_back_to_one_message_per_line() {
+        let state = state::server(Config::default());
+        let (alice, alice_client) = state::register(&state, "alice");
+        let (bob, bob_client) = state::register(&state, "bob");
+        let (carol, carol_client) = state::register(&state, "carol");
+        enable_multiline(&state, alice);
+        enable_multiline(&state, carol);
+        let mut users = state.users.lock().unwrap();
+        let bob_capabilities = &mut users.get_mut(&bob).unwrap().capabilities;
+        bob_capabilities.insert("message-tags".to_string());
+        drop(users);
+        for user_id in [alice, bob, carol] {
+            let join = Message::from("JOIN #rust").unwrap();
+            state.handlers.dispatch(join, &state, user_id).unwrap();
+        }
+
+        send(&state, alice, "#rust", &["hello", "+ world", "", "bye"]);
+
+        let messages = |user_id, client| {
+            state::disconnect(&state, user_id, client)
+                .iter()
+                .map(|line| Message::from(line).unwrap())
+                .filter(|message| matches!(message.command, Command::PrivMsg | Command::Batch))
+                .collect::<Vec<_>>()
+        };
+        assert!(messages(alice, alice_client).is_empty());
+        let batch = messages(carol, carol_client);
+        let texts = batch[1..4]
+            .iter()
+            .map(|line| line.params[1].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["hello", " world", ""]);
+        assert!(batch[2].tags.contains_key(CONCAT_TAG));
+        let msgid = &batch[0].tags["msgid"];
+
+        let fallback = messages(bob, bob_client);
+        let texts = fallback
+            .iter()
+            .map(|line| line.params[1].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(texts, ["hello world", "bye"]);
+        assert!(fallback.iter().all(|line| &line.tags["msgid"] == msgid));
+
+        let history = state.history.lock().unwrap();
+        let saved = history.query("#rust", &history::Query::Latest(None), 10);
+        assert_eq!(saved.len(), 2);
+        assert!(history.get("#rust", msgid).is_some());
+    }
+
+    #[test]
+    fn rejects_messages_over_the_limits() {
+        let state = state::server(Config {
+            multiline_max_lines: 2,
+            multiline_max_bytes: 10,
+            ..Config::default()
+        });
+        let (alice, client) = state::register(&state, "alice");
+        let (_bob, _bob_client) = state::register(&state, "bob");
+        enable_multiline(&state, alice);
+
+        send(&state, alice, "bob", &["a", "b", "c"]);
+        send(&state, alice, "bob", &["hello", "world"]);
+        send(&state, alice, "bob", &["hello", "+world"]);
+
+        let replies = state::disconnect(&state, alice, client);
+        assert_eq!(
+            replies,
+            [
+                ":127.0.0.1 FAIL BATCH MULTILINE_MAX_LINES 2 :The multi-line message has too many \
+                 lines.",
+                ":127.0.0.1 FAIL BATCH MULTILINE_MAX_BYTES 10 :The multi-line message is too long.",
+            ]
+        );
+    }
+}
@@ -0,0 +1,536 @@
+use crate::{
+    bans::{Ban, BanKind},
+    caps,
+    config::{Config, Privilege},
+    error::ServerError,
+    mask,
+    server::{disconnect_user, get_nickname_id, send_notice, send_to_user, CommandResponse},
+    state::{ServerState, ShutdownMode},
+};
+use shared::message::{Command, Message, ReplyCode, Response};
+use uuid::Uuid;
+
+pub fn handle_oper(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: OPER admin hunter2
+    let (name, password) = (&message.params[0], &message.params[1]);
+
+    let operator = state.config.read().unwrap().operator(name).cloned();
+
+    let mut lock = users.lock()?;
+    let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+    let nickname = user.nickname.clone().unwrap();
+
+    // The operator block has to exist and allow the host the user is connecting from
+    let user_host = format!("{}@{}", user.username.as_ref().unwrap(), user.hostname);
+    let operator = match operator {
+        Some(operator)
+            if operator
+                .hosts
+                .iter()
+                .any(|host| mask::matches(host, &user_host)) =>
+        {
+            operator
+        }
+        _ => {
+            drop(lock);
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOOPERHOST,
+                &["No operator block for your host."],
+            );
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    if !bcrypt::verify(password, &operator.password).unwrap_or(false) {
+        drop(lock);
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::ERR_PASSWDMISMATCH,
+            &["Password incorrect."],
+        );
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    user.operator = Some(operator.name);
+    let prefix = user.prefix();
+    drop(lock);
+
+    let response = Response::new(
+        server_prefix,
+        ReplyCode::RPL_YOUREOPER,
+        &["You are now an IRC operator."],
+    );
+    send_to_user(&response, users, user_id)?;
+    let mode = Message::new(prefix, Command::Mode, &[&nickname, "+o"]);
+    send_to_user(&mode, users, user_id)?;
+
+    Ok(CommandResponse::Continue)
+}
+
+pub fn handle_kill(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    if !has_privilege(state, user_id, Privilege::Kill)? {
+        return Ok(CommandResponse::Continue);
+    }
+
+    // Example: KILL spammer :Stop flooding
+    let nickname = message.params[0].clone();
+    let reason = message
+        .params
+        .get(1)
+        .map(String::as_str)
+        .unwrap_or("No reason given");
+
+    let target_id = match get_nickname_id(&nickname, users)? {
+        Some(id) => id,
+        None => {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOSUCHNICK,
+                &["The given nick was not found."],
+            );
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    let operator_nickname = users
+        .lock()?
+        .get(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .nickname
+        .clone()
+        .unwrap();
+    let reason = format!("Killed ({} ({}))", operator_nickname, reason);
+
+    let kill = Message::new(message.prefix.clone(), Command::Kill, &[&nickname, &reason]);
+    send_to_user(&kill, users, target_id)?;
+    disconnect_user(state, target_id, &reason)?;
+
+    Ok(CommandResponse::Continue)
+}
+
+pub fn handle_wallops(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+
+    if !has_privilege(state, user_id, Privilege::Wallops)? {
+        return Ok(CommandResponse::Continue);
+    }
+
+    // Example: WALLOPS :Rebooting in 5 minutes
+
+    // Wallops are seen by everyone with user mode +w
+    for (_, user) in users
+        .lock()?
+        .iter_mut()
+        .filter(|(_, user)| user.modes.wallops)
+    {
+        user.send(&message)?;
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Reload the configuration file. Listeners are only bound at startup, so changes to them take
+/// effect on the next restart.
+pub fn handle_rehash(state: &ServerState, user_id: Uuid) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    if !has_privilege(state, user_id, Privilege::Rehash)? {
+        return Ok(CommandResponse::Continue);
+    }
+
+    match Config::load(&state.config_path) {
+        Ok(config) => {
+            *state.config.write().unwrap() = config;
+            caps::refresh(state)?;
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::RPL_REHASHING,
+                &[&state.config_path, "Rehashing"],
+            );
+            send_to_user(&response, users, user_id)?;
+        }
+        Err(err) => {
+            send_notice(
+                state,
+                user_id,
+                &format!("Failed to reload configuration: {}", err),
+            )?;
+        }
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Handle both DIE and RESTART, which disconnect everyone before stopping the server. RESTART then
+/// replaces the process with a fresh copy of the server started with the same arguments.
+pub fn handle_die(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let restart = matches!(message.command, Command::Restart);
+    let privilege = if restart {
+        Privilege::Restart
+    } else {
+        Privilege::Die
+    };
+    if !has_privilege(state, user_id, privilege)? {
+        return Ok(CommandResponse::Continue);
+    }
+
+    // The main thread tells everyone and closes their connections
+    state.request_shutdown(if restart {
+        ShutdownMode::Restart
+    } else {
+        ShutdownMode::Exit
+    });
+    Ok(CommandResponse::Continue)
+}
+
+/// Handle both KLINE and DLINE, which ban a `user@host` mask or an IP network from the server,
+/// optionally for a number of minutes. Connected users matching the ban are disconnected.
+pub fn handle_ban(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    if !has_privilege(state, user_id, Privilege::Ban)? {
+        return Ok(CommandResponse::Continue);
+    }
+
+    // Example: KLINE 60 *@spammer.example.com :Spamming
+    //          DLINE 10.0.0.0/8 :Abuse
+    let (kind, name) = match message.command {
+        Command::Kline => (BanKind::KLine, "K-line"),
+        _ => (BanKind::DLine, "D-line"),
+    };
+
+    // The duration in minutes is optional
+    let mut params = message.params.as_slice();
+    let minutes = params.first().and_then(|param| param.parse::<u64>().ok());
+    if minutes.is_some() {
+        params = &params[1..];
+    }
+
+    let mask = match params.first() {
+        // A K-line on just a host bans every user on it
+        Some(mask) if kind == BanKind::KLine && !mask.contains('@') => format!("*@{}", mask),
+        Some(mask) => mask.clone(),
+        None => {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_NEEDMOREPARAMS,
+                &[
+                    &message.command.to_string().to_uppercase(),
+                    "Not enough parameters.",
+                ],
+            );
+            send_to_user(&response, users, user_id)?;
+            return Ok(CommandResponse::Continue);
+        }
+    };
+    let reason = params
+        .get(1)
+        .map(String::as_str)
+        .unwrap_or("No reason given");
+
+    // A ban on everyone would also cover the operator setting it, and would be saved for good
+    if mask::matches_everything(&mask) {
+        let notice = format!(
+            "Not adding a {} for {}, since it matches everyone.",
+            name, mask
+        );
+        send_notice(state, user_id, &notice)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let operator_nickname = users
+        .lock()?
+        .get(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .nickname
+        .clone()
+        .unwrap();
+    let ban = Ban::new(&mask, reason, &operator_nickname, minutes);
+    state.bans.lock().unwrap().add(kind, ban)?;
+    send_notice(state, user_id, &format!("Added {} for {}.", name, mask))?;
+
+    let matching_users = users
+        .lock()?
+        .values()
+        .filter(|user| match kind {
+            BanKind::KLine => user.username.as_ref().is_some_and(|username| {
+                mask::matches(&mask, &format!("{}@{}", username, user.hostname))
+            }),
+            BanKind::DLine => user
+                .hostname
+                .parse()
+                .is_ok_and(|address| mask::matches_network(&mask, address)),
+        })
+        .map(|user| user.id)
+        .collect::<Vec<_>>();
+    for id in matching_users {
+        disconnect_user(state, id, &format!("{} ({})", name, reason))?;
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Handle both UNKLINE and UNDLINE.
+pub fn handle_unban(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    if !has_privilege(state, user_id, Privilege::Ban)? {
+        return Ok(CommandResponse::Continue);
+    }
+
+    // Example: UNKLINE *@spammer.example.com
+    let (kind, name) = match message.command {
+        Command::Unkline => (BanKind::KLine, "K-line"),
+        _ => (BanKind::DLine, "D-line"),
+    };
+    let mask = &message.params[0];
+    let mask = if kind == BanKind::KLine && !mask.contains('@') {
+        format!("*@{}", mask)
+    } else {
+        mask.clone()
+    };
+
+    let removed = state.bans.lock().unwrap().remove(kind, &mask)?;
+    let notice = if removed {
+        format!("Removed {} for {}.", name, mask)
+    } else {
+        format!("There is no {} for {}.", name, mask)
+    };
+    send_notice(state, user_id, &notice)?;
+
+    Ok(CommandResponse::Continue)
+}
+
+/// List K-lines with `STATS k` and D-lines with `STATS d`. Other reports aren't supported, so they
+/// come back empty.
+pub fn handle_stats(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    let query = message.params[0].clone();
+
+    let report = match query.to_lowercase().as_str() {
+        "k" => Some((BanKind::KLine, ReplyCode::RPL_STATSKLINE)),
+        "d" => Some((BanKind::DLine, ReplyCode::RPL_STATSDLINE)),
+        _ => None,
+    };
+    if let Some((kind, code)) = report {
+        if !has_privilege(state, user_id, Privilege::Ban)? {
+            return Ok(CommandResponse::Continue);
+        }
+
+        let bans = state
+            .bans
+            .lock()
+            .unwrap()
+            .list(kind)
+            .cloned()
+            .collect::<Vec<_>>();
+        for ban in bans {
+            let response = Response::new(server_prefix, code, &[&ban.mask, &ban.describe()]);
+            send_to_user(&response, users, user_id)?;
+        }
+    }
+
+    let response = Response::new(
+        server_prefix,
+        ReplyCode::RPL_ENDOFSTATS,
+        &[&query, "End of STATS report"],
+    );
+    send_to_user(&response, users, user_id)?;
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Check whether the user is an operator with the given privilege. If they aren't, they are sent
+/// ERR_NOPRIVILEGES.
+fn has_privilege(
+    state: &ServerState,
+    user_id: Uuid,
+    privilege: Privilege,
+) -> Result<bool, ServerError> {
+    let operator = state
+        .users
+        .lock()?
+        .get(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .operator
+        .clone();
+
+    let allowed = operator
+        .and_then(|name| {
+            state
+                .config
+                .read()
+                .unwrap()
+                .operator(&name)
+                .map(|operator| operator.privileges.contains(&privilege))
+        })
+        .unwrap_or(false);
+
+    if !allowed {
+        let response = Response::new(
+            &state.server_name(),
+            ReplyCode::ERR_NOPRIVILEGES,
+            &["Permission denied: you don't have the required operator privileges."],
+        );
+        send_to_user(&response, &state.users, user_id)?;
+    }
+
+    Ok(allowed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Operator, state::tests as state};
+
+    #[test]
+    fn privileges_are_checked_per_operator() {
+        let state = state::server(Config {
+            operators: vec![Operator {
+                name: "admin".to_string(),
+                password: String::new(),
+                hosts: vec![],
+                privileges: vec![Privilege::Kill],
+            }],
+            ..Config::default()
+        });
+        let (user_id, client) = state::connect(&state);
+
+        assert!(!has_privilege(&state, user_id, Privilege::Kill).unwrap());
+        state
+            .users
+            .lock()
+            .unwrap()
+            .get_mut(&user_id)
+            .unwrap()
+            .operator = Some("admin".to_string());
+        assert!(has_privilege(&state, user_id, Privilege::Kill).unwrap());
+        assert!(!has_privilege(&state, user_id, Privilege::Die).unwrap());
+
+        let replies = state::disconnect(&state, user_id, client);
+        assert_eq!(replies.len(), 2);
+        assert!(replies
+            .iter()
+            .all(|line| line.starts_with(":127.0.0.1 481 ")));
+    }
+
+    #[test]
+    fn die_and_restart_request_shutdown() {
+        let state = state::server(Config {
+            operators: vec![Operator {
+                name: "admin".to_string(),
+                password: String::new(),
+                hosts: vec![],
+                privileges: vec![Privilege::Die],
+            }],
+            ..Config::default()
+        });
+        let (user_id, _client) = state::register(&state, "alice");
+        state
+            .users
+            .lock()
+            .unwrap()
+            .get_mut(&user_id)
+            .unwrap()
+            .operator = Some("admin".to_string());
+        let send = |line: &str| {
+            let message = Message::from(line).unwrap();
+            state.handlers.dispatch(message, &state, user_id).unwrap();
+        };
+
+        send("RESTART");
+        assert!(!state.is_shutting_down());
+        send("DIE");
+        assert!(state.is_shutting_down());
+
+        // Only the first request counts
+        state.request_shutdown(ShutdownMode::Restart);
+        assert_eq!(state.wait_for_shutdown(), ShutdownMode::Exit);
+    }
+
+    #[test]
+    fn refuses_bans_on_everyone() {
+        let state = state::server(Config {
+            operators: vec![Operator {
+                name: "admin".to_string(),
+                password: String::new(),
+                hosts: vec![],
+                privileges: vec![Privilege::Ban],
+            }],
+            ..Config::default()
+        });
+        let (user_id, client) = state::register(&state, "alice");
+        state
+            .users
+            .lock()
+            .unwrap()
+            .get_mut(&user_id)
+            .unwrap()
+            .operator = Some("admin".to_string());
+        for line in [
+            "KLINE *",
+            "KLINE 60 *@* :Everyone",
+            "DLINE *",
+            "DLINE 0.0.0.0/0",
+        ] {
+            let message = Message::from(line).unwrap();
+            state.handlers.dispatch(message, &state, user_id).unwrap();
+        }
+
+        let bans = state.bans.lock().unwrap();
+        assert_eq!(bans.list(BanKind::KLine).count(), 0);
+        assert_eq!(bans.list(BanKind::DLine).count(), 0);
+        drop(bans);
+        let replies = state::disconnect(&state, user_id, client);
+        assert_eq!(
+            replies,
+            [
+                ":127.0.0.1 NOTICE alice :Not adding a K-line for *@*, since it matches everyone.",
+                ":127.0.0.1 NOTICE alice :Not adding a K-line for *@*, since it matches everyone.",
+                ":127.0.0.1 NOTICE alice :Not adding a D-line for *, since it matches everyone.",
+                ":127.0.0.1 NOTICE alice :Not adding a D-line for 0.0.0.0/0, since it matches \
+                 everyone.",
+            ]
+        );
+    }
+}
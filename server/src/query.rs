@@ -0,0 +1,210 @@
+use crate::{
+    error::ServerError,
+    mask,
+    server::{send_to_user, CommandResponse},
+    state::ServerState,
+};
+use shared::message::{Message, ReplyCode, Response};
+use uuid::Uuid;
+
+pub fn handle_names(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: NAMES #rust,#irc
+    //          NAMES
+    let channel_names = match message.params.first() {
+        Some(names) => names.split(',').map(String::from).collect::<Vec<_>>(),
+        None => state.channels.lock().unwrap().keys().cloned().collect(),
+    };
+
+    let mut responses = vec![];
+    {
+        let lock = users.lock()?;
+        let viewer = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+
+        for name in &channel_names {
+            let nicknames = lock
+                .values()
+                .filter(|user| user.channel.as_ref().map(|channel| &channel.name) == Some(name))
+                .filter(|user| user.is_visible_to(viewer))
+                .map(|user| {
+                    // Channel operators are marked with an @
+                    let channel = user.channel.as_ref().unwrap();
+                    let status = if channel.is_operator(user.id) {
+                        "@"
+                    } else {
+                        ""
+                    };
+                    format!("{}{}", status, user.nickname.as_ref().unwrap())
+                })
+                .collect::<Vec<_>>();
+
+            if !nicknames.is_empty() {
+                responses.push(Response::new(
+                    server_prefix,
+                    ReplyCode::RPL_NAMREPLY,
+                    &["=", name, &nicknames.join(" ")],
+                ));
+            }
+            if !message.params.is_empty() {
+                responses.push(Response::new(
+                    server_prefix,
+                    ReplyCode::RPL_ENDOFNAMES,
+                    &[name, "End of NAMES list"],
+                ));
+            }
+        }
+    }
+
+    if message.params.is_empty() {
+        responses.push(Response::new(
+            server_prefix,
+            ReplyCode::RPL_ENDOFNAMES,
+            &["*", "End of NAMES list"],
+        ));
+    }
+
+    for response in responses {
+        send_to_user(&response, users, user_id)?;
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+pub fn handle_who(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: WHO #rust
+    //          WHO ali*
+    let target = message
+        .params
+        .first()
+        .cloned()
+        .unwrap_or_else(|| "*".to_string());
+
+    let mut responses = vec![];
+    {
+        let lock = users.lock()?;
+        let viewer = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+
+        for user in lock.values().filter(|user| user.is_registered) {
+            let channel = user.channel.as_ref().map(|channel| channel.name.as_str());
+            let matches = if target.starts_with('#') {
+                channel == Some(target.as_str())
+            } else {
+                mask::matches(&target, user.nickname.as_ref().unwrap())
+            };
+            if !matches || !user.is_visible_to(viewer) {
+                continue;
+            }
+
+            // H(ere) or G(one), followed by * for operators and @ for channel operators
+            let mut flags = if user.is_away { "G" } else { "H" }.to_string();
+            if user.operator.is_some() {
+                flags.push('*');
+            }
+            if let Some(channel) = &user.channel {
+                if channel.is_operator(user.id) {
+                    flags.push('@');
+                }
+            }
+
+            responses.push(Response::new(
+                server_prefix,
+                ReplyCode::RPL_WHOREPLY,
+                &[
+                    channel.unwrap_or("*"),
+                    user.username.as_ref().unwrap(),
+                    &user.hostname,
+                    server_prefix,
+                    user.nickname.as_ref().unwrap(),
+                    &flags,
+                    &format!("0 {}", user.realname.as_deref().unwrap_or("")),
+                ],
+            ));
+        }
+    }
+
+    responses.push(Response::new(
+        server_prefix,
+        ReplyCode::RPL_ENDOFWHO,
+        &[&target, "End of WHO list"],
+    ));
+    for response in responses {
+        send_to_user(&response, users, user_id)?;
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, state::tests as state, user::Channel};
+    use std::sync::Arc;
+
+    #[test]
+    fn invisible_users_are_hidden_without_a_shared_channel() {
+        let state = state::server(Config::default());
+        let channel = Arc::new(Channel::new("#rust"));
+        state
+            .channels
+            .lock()
+            .unwrap()
+            .insert("#rust".to_string(), channel.clone());
+        let (alice, alice_client) = state::register(&state, "alice");
+        let (bob, bob_client) = state::register(&state, "bob");
+        let (carol, carol_client) = state::register(&state, "carol");
+        for user_id in [alice, bob] {
+            let mut users = state.users.lock().unwrap();
+            users.get_mut(&user_id).unwrap().channel = Some(channel.clone());
+        }
+        for user_id in [alice, carol] {
+            let mut users = state.users.lock().unwrap();
+            users.get_mut(&user_id).unwrap().modes.invisible = true;
+        }
+
+        for user_id in [alice, bob, carol] {
+            for line in ["WHO *", "NAMES #rust"] {
+                let message = Message::from(line).unwrap();
+                state.handlers.dispatch(message, &state, user_id).unwrap();
+            }
+        }
+
+        // Return the nicknames listed by WHO and by NAMES, e.g. "WHO alice" and "NAMES alice"
+        let listed = |user_id, client| {
+            let mut listed = vec![];
+            for line in state::disconnect(&state, user_id, client) {
+                let params = line.split(' ').collect::<Vec<_>>();
+                match params[1] {
+                    "352" => listed.push(format!("WHO {}", params[6])),
+                    "353" => listed.extend(
+                        params[4..]
+                            .iter()
+                            .map(|nickname| format!("NAMES {}", nickname.trim_start_matches(':'))),
+                    ),
+                    _ => {}
+                }
+            }
+            listed.sort();
+            listed
+        };
+        let everyone = ["NAMES alice", "NAMES bob", "WHO alice", "WHO bob"];
+        assert_eq!(listed(alice, alice_client), everyone);
+        assert_eq!(listed(bob, bob_client), everyone);
+        assert_eq!(
+            listed(carol, carol_client),
+            ["NAMES bob", "WHO bob", "WHO carol"]
+        );
+    }
+}
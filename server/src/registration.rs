@@ -0,0 +1,285 @@
+use crate::{
+    config::NickEnforcement,
+    error::ServerError,
+    monitor, sasl,
+    server::{
+        broadcast_to_all, disconnect_user, nickname_in_use, send_notice, send_to_user,
+        CommandResponse,
+    },
+    state::ServerState,
+};
+use shared::message::{Command, Message};
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+/// Shortest password an account can be registered with.
+const MIN_PASSWORD_LENGTH: usize = 8;
+/// Longest name an account can have.
+const MAX_ACCOUNT_LENGTH: usize = 32;
+
+pub fn handle_register(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: REGISTER alice alice@example.com :correct horse battery staple
+    //          REGISTER * * hunter22
+    let (account, email, password) = (
+        message.params[0].clone(),
+        message.params[1].clone(),
+        message.params[2].clone(),
+    );
+
+    let (nickname, logged_in) = {
+        let lock = users.lock()?;
+        let user = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+        (user.nickname.clone().unwrap(), user.account.is_some())
+    };
+
+    // An account of * is named after the user's nickname
+    let account = if account == "*" { nickname } else { account };
+    let email = Some(email).filter(|email| email != "*");
+
+    let fail = |code: &str, text: &str| {
+        Message::new(
+            Some(server_prefix.to_string()),
+            Command::Fail,
+            &["REGISTER", code, &account, text],
+        )
+    };
+    let error = if logged_in {
+        Some(fail(
+            "ALREADY_AUTHENTICATED",
+            "You're already logged in to an account.",
+        ))
+    } else if !is_valid_account_name(&account) {
+        Some(fail("BAD_ACCOUNT_NAME", "That account name isn't allowed."))
+    } else if password.len() < MIN_PASSWORD_LENGTH {
+        Some(fail(
+            "WEAK_PASSWORD",
+            &format!(
+                "Passwords need at least {} characters.",
+                MIN_PASSWORD_LENGTH
+            ),
+        ))
+    } else if state.accounts.lock().unwrap().get(&account).is_some() {
+        Some(fail("ACCOUNT_EXISTS", "That account already exists."))
+    } else {
+        None
+    };
+    if let Some(error) = error {
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let hash = bcrypt::hash(&password, bcrypt::DEFAULT_COST)?;
+    let created = state
+        .accounts
+        .lock()
+        .unwrap()
+        .register(&account, &hash, email)?;
+    if !created {
+        // Someone else registered the same name while the password was being hashed
+        send_to_user(
+            &fail("ACCOUNT_EXISTS", "That account already exists."),
+            users,
+            user_id,
+        )?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let success = Message::new(
+        Some(server_prefix.to_string()),
+        Command::Register,
+        &["SUCCESS", &account, "Account created."],
+    );
+    send_to_user(&success, users, user_id)?;
+    sasl::log_in(state, user_id, &account)?;
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Check whether the user's nickname belongs to an account they aren't logged in to. If it does,
+/// warn them that they have until the grace period is over to log in or change nicknames.
+pub fn check_nickname(state: &ServerState, user_id: Uuid) -> Result<(), ServerError> {
+    let grace_period = state.config.read().unwrap().nick_grace_period;
+
+    let warn = {
+        let mut lock = state.users.lock()?;
+        let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+        let owner = match &user.nickname {
+            Some(nickname) => state
+                .accounts
+                .lock()
+                .unwrap()
+                .get(nickname)
+                .map(|account| account.name.clone()),
+            None => None,
+        };
+
+        let is_owner = owner.is_none()
+            || user
+                .account
+                .as_ref()
+                .is_some_and(|account| account.eq_ignore_ascii_case(owner.as_ref().unwrap()));
+        if is_owner {
+            user.nick_deadline = None;
+            false
+        } else if user.nick_deadline.is_none() {
+            user.nick_deadline = Some(Instant::now() + Duration::from_secs(grace_period));
+            true
+        } else {
+            false
+        }
+    };
+
+    if warn {
+        send_notice(
+            state,
+            user_id,
+            &format!(
+                "This nickname is registered. Log in or change nicknames within {} seconds.",
+                grace_period
+            ),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Rename or disconnect users who are still using someone else's nickname once their grace period
+/// is over. This runs on its own thread for as long as the server does.
+pub fn enforce_nicknames(state: Arc<ServerState>) {
+    loop {
+        thread::sleep(Duration::from_secs(1));
+
+        let now = Instant::now();
+        let expired = match state.users.lock() {
+            Ok(users) => users
+                .values()
+                .filter(|user| user.nick_deadline.is_some_and(|deadline| deadline <= now))
+                .map(|user| user.id)
+                .collect::<Vec<_>>(),
+            Err(err) => {
+                println!("Stopped enforcing nicknames: {}", ServerError::from(err));
+                return;
+            }
+        };
+
+        for user_id in expired {
+            if let Err(err) = enforce_nickname(&state, user_id) {
+                println!("Failed to enforce nickname: {}", err);
+            }
+        }
+    }
+}
+
+fn enforce_nickname(state: &ServerState, user_id: Uuid) -> Result<(), ServerError> {
+    let users = &state.users;
+
+    // The user may have quit or logged in since the deadline was checked
+    let is_pending = users
+        .lock()?
+        .get(&user_id)
+        .is_some_and(|user| user.nick_deadline.is_some());
+    if !is_pending {
+        return Ok(());
+    }
+
+    if state.config.read().unwrap().nick_enforcement == NickEnforcement::Disconnect {
+        return disconnect_user(state, user_id, "Nickname enforcement");
+    }
+
+    // Pick a guest nickname nobody is using
+    let guest = loop {
+        let guest = format!("Guest{}", Uuid::new_v4().as_u128() % 100_000);
+        if !nickname_in_use(&guest, users)? {
+            break guest;
+        }
+    };
+
+    let (prefix, is_registered) = {
+        let mut lock = users.lock()?;
+        let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+        let prefix = user.prefix();
+        user.nickname = Some(guest.clone());
+        user.nick_deadline = None;
+        (prefix, user.is_registered)
+    };
+
+    let nick = Message::new(prefix.clone(), Command::Nick, &[&guest]);
+    if is_registered {
+        broadcast_to_all(&nick, users)?;
+        let old_nickname = prefix
+            .as_deref()
+            .and_then(|prefix| prefix.split('!').next());
+        monitor::notify_offline(state, old_nickname.unwrap())?;
+        let new_prefix = users.lock()?.get(&user_id).and_then(|user| user.prefix());
+        monitor::notify_online(state, &new_prefix.unwrap())
+    } else {
+        send_to_user(&nick, users, user_id)
+    }
+}
+
+/// Account names follow the same rules as nicknames, so that an account can reserve the nickname
+/// it's named after.
+fn is_valid_account_name(name: &str) -> bool {
+    let special = |c: char| "-_[]{}\\|^`".contains(c);
+    name.len() <= MAX_ACCOUNT_LENGTH
+        && name.starts_with(|c: char| c.is_ascii_alphabetic() || special(c))
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || special(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{accounts::AccountStore, config::Config, state::tests as state};
+    use std::{env, fs};
+
+    #[test]
+    fn account_names_follow_nickname_rules() {
+        assert!(is_valid_account_name("alice"));
+        assert!(is_valid_account_name("[bot]_2"));
+        assert!(!is_valid_account_name("2alice"));
+        assert!(!is_valid_account_name("alice smith"));
+        assert!(!is_valid_account_name(&"a".repeat(MAX_ACCOUNT_LENGTH + 1)));
+    }
+
+    #[test]
+    fn registered_nicknames_need_their_account() {
+        let path = env::temp_dir().join(format!("accounts-{}.json", Uuid::new_v4()));
+        let mut accounts = AccountStore::load(path.to_str().unwrap()).unwrap();
+        accounts.register("Alice", "hash", None).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let state = state::server(Config::default());
+        *state.accounts.lock().unwrap() = accounts;
+        let (user_id, client) = state::register(&state, "alice");
+        let deadline = || state.users.lock().unwrap()[&user_id].nick_deadline;
+
+        check_nickname(&state, user_id).unwrap();
+        assert!(deadline().is_some());
+        state
+            .users
+            .lock()
+            .unwrap()
+            .get_mut(&user_id)
+            .unwrap()
+            .account = Some("ALICE".to_string());
+        check_nickname(&state, user_id).unwrap();
+        assert!(deadline().is_none());
+
+        let replies = state::disconnect(&state, user_id, client);
+        assert_eq!(replies.len(), 1);
+        assert!(replies[0].starts_with(":127.0.0.1 NOTICE alice :This nickname is registered."));
+    }
+}
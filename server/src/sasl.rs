@@ -0,0 +1,221 @@
+use crate::{
+    error::ServerError,
+    registration,
+    server::{send_to_user, CommandResponse},
+    state::ServerState,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use shared::message::{Command, Message, ReplyCode, Response};
+use uuid::Uuid;
+
+/// Longest piece of data a client can send with one AUTHENTICATE. A piece of exactly this length
+/// means more data follows.
+const CHUNK_LENGTH: usize = 400;
+/// Most data a client can send in one exchange, so it can't make the server buffer it forever.
+const MAX_SASL_LENGTH: usize = 4 * CHUNK_LENGTH;
+
+pub fn handle_authenticate(
+    message: Message,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: AUTHENTICATE PLAIN
+    //          AUTHENTICATE AGFsaWNlAGh1bnRlcjI=
+    //          AUTHENTICATE *
+    let data = message.params[0].clone();
+
+    // The exchange is taken out of the user and only put back if it continues
+    let (target, enabled, logged_in, buffer) = {
+        let mut lock = users.lock()?;
+        let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+        (
+            user.nickname.clone().unwrap_or_else(|| "*".to_string()),
+            user.capabilities.contains("sasl"),
+            user.account.is_some(),
+            user.sasl_buffer.take(),
+        )
+    };
+    let reply = |code, text: &str| Response::new(server_prefix, code, &[&target, text]);
+
+    if !enabled {
+        let response = reply(ReplyCode::ERR_SASLFAIL, "SASL authentication failed.");
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+    if logged_in {
+        let response = reply(
+            ReplyCode::ERR_SASLALREADY,
+            "You have already authenticated using SASL.",
+        );
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+    if data == "*" {
+        let response = reply(ReplyCode::ERR_SASLABORTED, "SASL authentication aborted.");
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    let mut buffer = match buffer {
+        Some(buffer) => buffer,
+        // There's no exchange going on yet, so the client is picking a mechanism. EXTERNAL needs a
+        // TLS client certificate, which plain TCP listeners never have, so only PLAIN is offered.
+        None => {
+            if data.eq_ignore_ascii_case("PLAIN") {
+                users
+                    .lock()?
+                    .get_mut(&user_id)
+                    .ok_or(ServerError::NoSuchUser)?
+                    .sasl_buffer = Some(String::new());
+                let challenge = Message::new(None, Command::Authenticate, &["+"]);
+                send_to_user(&challenge, users, user_id)?;
+            } else {
+                let response = Response::new(
+                    server_prefix,
+                    ReplyCode::RPL_SASLMECHS,
+                    &[&target, "PLAIN", "are available SASL mechanisms"],
+                );
+                send_to_user(&response, users, user_id)?;
+                let response = reply(ReplyCode::ERR_SASLFAIL, "SASL authentication failed.");
+                send_to_user(&response, users, user_id)?;
+            }
+            return Ok(CommandResponse::Continue);
+        }
+    };
+
+    if data.len() > CHUNK_LENGTH || buffer.len() + data.len() > MAX_SASL_LENGTH {
+        let response = reply(ReplyCode::ERR_SASLTOOLONG, "SASL message too long.");
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
+    }
+
+    // A lone + is an empty piece, which ends the data when the last piece was exactly full
+    if data != "+" {
+        buffer.push_str(&data);
+    }
+    if data.len() == CHUNK_LENGTH {
+        users
+            .lock()?
+            .get_mut(&user_id)
+            .ok_or(ServerError::NoSuchUser)?
+            .sasl_buffer = Some(buffer);
+        return Ok(CommandResponse::Continue);
+    }
+
+    // The data is `authzid\0authcid\0password`. Users can't act on behalf of another account, so
+    // the authorization identity has to be empty or the same as the account being logged in to.
+    let account = decode_plain(&buffer)
+        .filter(|(authzid, authcid, _)| authzid.is_empty() || authzid == authcid)
+        .and_then(|(_, authcid, password)| {
+            state
+                .accounts
+                .lock()
+                .unwrap()
+                .authenticate(&authcid, &password)
+                .map(|account| account.name.clone())
+        });
+
+    match account {
+        Some(account) => {
+            log_in(state, user_id, &account)?;
+            let response = reply(
+                ReplyCode::RPL_SASLSUCCESS,
+                "SASL authentication successful.",
+            );
+            send_to_user(&response, users, user_id)?;
+        }
+        None => {
+            let response = reply(ReplyCode::ERR_SASLFAIL, "SASL authentication failed.");
+            send_to_user(&response, users, user_id)?;
+        }
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Log the user in to an account, and tell the users they share a channel with that asked for
+/// `account-notify`.
+pub fn log_in(state: &ServerState, user_id: Uuid, account: &str) -> Result<(), ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    let (target, prefix, channel) = {
+        let mut lock = users.lock()?;
+        let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+        user.account = Some(account.to_string());
+        user.modes.registered = true;
+        // Accounts can have a connection class of their own
+        user.class = state
+            .config
+            .read()
+            .unwrap()
+            .class_for(&user.hostname, Some(account))
+            .map(|class| class.name.clone());
+        (
+            user.nickname.clone().unwrap_or_else(|| "*".to_string()),
+            user.prefix(),
+            user.channel.clone(),
+        )
+    };
+
+    let response = Response::new(
+        server_prefix,
+        ReplyCode::RPL_LOGGEDIN,
+        &[
+            &target,
+            prefix.as_deref().unwrap_or("*"),
+            account,
+            &format!("You are now logged in as {}", account),
+        ],
+    );
+    send_to_user(&response, users, user_id)?;
+
+    if let Some(channel) = channel {
+        let notify = Message::new(prefix, Command::Account, &[account]);
+        users
+            .lock()?
+            .values_mut()
+            .filter(|user| user.id != user_id && user.channel.as_ref() == Some(&channel))
+            .filter(|user| user.capabilities.contains("account-notify"))
+            .try_for_each(|user| user.send(&notify))?;
+    }
+
+    // Logging in to the account that owns the user's nickname lets them keep it
+    registration::check_nickname(state, user_id)
+}
+
+/// Split decoded PLAIN data into the authorization identity, authentication identity, and
+/// password.
+fn decode_plain(data: &str) -> Option<(String, String, String)> {
+    let decoded = String::from_utf8(STANDARD.decode(data).ok()?).ok()?;
+    let mut parts = decoded.splitn(3, '\0').map(String::from);
+    Some((parts.next()?, parts.next()?, parts.next()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_plain_credentials() {
+        let encode = |text: &str| STANDARD.encode(text);
+        assert_eq!(
+            decode_plain(&encode("\0alice\0hunter2")),
+            Some((String::new(), "alice".to_string(), "hunter2".to_string()))
+        );
+        // Only the first two separators count, so passwords can contain NUL
+        assert_eq!(
+            decode_plain(&encode("admin\0alice\0pass\0word")),
+            Some((
+                "admin".to_string(),
+                "alice".to_string(),
+                "pass\0word".to_string()
+            ))
+        );
+        assert_eq!(decode_plain(&encode("alice\0hunter2")), None);
+        assert_eq!(decode_plain("not base64!"), None);
+    }
+}
@@ -1,218 +1,464 @@
 use crate::{
-    message::{Command, Message, ReplyCode, Response, ToIrc},
+    caps, channels,
+    error::ServerError,
+    flood::{self, TokenBucket},
+    handler::{Registry, Requirements},
+    history, modes, monitor, multiline, oper, query, registration, sasl,
+    state::{ServerState, UserTable},
     user::{Channel, User},
 };
+use chrono::Utc;
+use shared::{
+    command::ClientCommand,
+    message::{Command, Message, ReplyCode, Response, ToIrc, MAX_CLIENT_TAGS_LENGTH},
+};
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
-    net::TcpStream,
+    collections::VecDeque,
+    io::{ErrorKind, Read, Write},
+    net::{IpAddr, TcpStream},
     str::{self},
-    sync::{Arc, Mutex},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 use uuid::Uuid;
 
-type UserTable = Mutex<HashMap<Uuid, User>>;
-type ChannelTable = Mutex<HashMap<String, Arc<Channel>>>;
-
-#[derive(PartialEq)]
-enum CommandResponse {
+#[derive(Debug, PartialEq)]
+pub(crate) enum CommandResponse {
     Continue,
     Quit,
 }
 
-pub fn handle_connection(
-    mut stream: TcpStream,
-    users: Arc<UserTable>,
-    channels: Arc<ChannelTable>,
-    hostname: &str,
-) {
-    let address = stream
-        .local_addr()
-        .expect("Failed to get IP address of client socket.")
-        .ip();
+pub fn handle_connection(mut stream: TcpStream, state: Arc<ServerState>, listener: &str) {
+    let users = &state.users;
+    let hostname = &state.server_name();
+    let address = match stream.peer_addr() {
+        Ok(address) => address.ip(),
+        Err(err) => {
+            println!("Failed to get the address of a connection: {err}");
+            return;
+        }
+    };
+
+    // Turn the connection away if its address is D-lined
+    let dline = state.bans.lock().unwrap().find_dline(address).cloned();
+    if let Some(ban) = dline {
+        let error = Message::new(
+            Some(hostname.to_string()),
+            Command::Error,
+            &[&format!("Closing link: D-line ({})", ban.reason)],
+        );
+        let _ = stream.write_all(error.to_irc().as_bytes());
+        return;
+    }
 
     // Add new user to the table
-    let user_id = {
-        let mut lock = users.lock().expect("Failed to lock the users table.");
-        let user = User::new(address, stream.try_clone().unwrap());
-        let id = user.id;
-        lock.insert(id, user);
+    let mut user = match stream
+        .try_clone()
+        .and_then(|writer| User::new(address, writer))
+    {
+        Ok(user) => user,
+        Err(err) => {
+            println!("Failed to set up the connection from {address}: {err}");
+            return;
+        }
+    };
+    user.listener = listener.to_string();
+    user.class = state
+        .config
+        .read()
+        .unwrap()
+        .class_for(&user.hostname, None)
+        .map(|class| class.name.clone());
+    let user_id = user.id;
+    {
+        let mut lock = match users.lock() {
+            Ok(lock) => lock,
+            Err(err) => {
+                println!("Failed to add the connection from {address}: {err}");
+                return;
+            }
+        };
+        lock.insert(user_id, user);
         println!(
             "New connection from {}. {} active connections.",
             address,
             lock.len()
         );
-        id
+    }
+
+    serve_user(&mut stream, &state, user_id, address);
+}
+
+/// Serve a user's connection until it ends, then remove them from the table, telling the users
+/// they share a channel with why they left.
+fn serve_user(stream: &mut TcpStream, state: &ServerState, user_id: Uuid, address: IpAddr) {
+    // However the connection ends, even if handling a command panicked, the user is removed from
+    // the table once this is dropped
+    let mut departure = Departure {
+        state,
+        user_id,
+        reason: "Connection closed".to_string(),
     };
+    if let Err(err) = serve_connection(stream, state, user_id) {
+        println!("Closing the connection from {address}: {err}");
+        departure.reason = err.to_string();
+    }
 
+    // A user whose SendQ filled up had their connection closed while a message was being sent to
+    // them, so tell everyone else why they left
+    let is_sendq_exceeded = state
+        .users
+        .lock()
+        .is_ok_and(|lock| lock.get(&user_id).is_some_and(|user| user.sendq_exceeded));
+    if is_sendq_exceeded {
+        departure.reason = "SendQ exceeded".to_string();
+    }
+}
+
+/// A user whose connection is being served, removed from the table when it's dropped.
+struct Departure<'a> {
+    state: &'a ServerState,
+    user_id: Uuid,
+    /// Reason given to the users they share a channel with
+    reason: String,
+}
+
+impl Drop for Departure<'_> {
+    fn drop(&mut self) {
+        // A panic while the users table was locked leaves it poisoned, but the user has to be
+        // removed from it regardless so they don't linger without a connection
+        if thread::panicking() {
+            self.state.users.clear_poison();
+            self.state.channels.clear_poison();
+            self.reason = "Internal error".to_string();
+        }
+        if let Err(err) = remove_user(self.state, self.user_id, &self.reason) {
+            println!("Failed to remove user {}: {}", self.user_id, err);
+        }
+    }
+}
+
+/// Read and handle the client's commands until it quits or the connection is closed.
+fn serve_connection(
+    stream: &mut TcpStream,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<(), ServerError> {
+    let users = &state.users;
+    let hostname = &state.server_name();
+
+    // Bytes received that don't make up a whole line yet
+    let mut buffer = vec![];
+    // Lines received but held back by flood protection until the connection has tokens for them
+    let mut queue: VecDeque<Vec<u8>> = VecDeque::new();
+    let mut bucket = TokenBucket::new();
+    // When the client last sent anything, and whether it's been sent a PING since
+    let mut last_active = Instant::now();
+    let mut is_pinged = false;
     loop {
-        // Wait for message from client
+        // Operators are exempt from flood protection. The limits are looked up each time so that
+        // REHASH, and logging in to an account with a class of its own, apply right away.
+        let (limits, is_exempt) = {
+            let mut lock = users.lock()?;
+            let user = match lock.get_mut(&user_id) {
+                Some(user) => user,
+                // Stop serving the connection if an operator has killed it
+                None => return Ok(()),
+            };
+            let limits = state.config.read()?.limits(user.class.as_deref());
+            user.sendq = limits.sendq;
+            (limits, user.operator.is_some())
+        };
+
+        // Handle queued lines for as long as there are tokens for them
+        while let Some(line) = queue.front() {
+            let message_str = decode_line(line).replace('\0', "");
+
+            // Extract IRC command from client input
+            let message = Message::from(&message_str);
+            let weight = message
+                .as_ref()
+                .map_or(1.0, |message| flood::weight(&message.command));
+            if !is_exempt && !bucket.try_take(&limits.flood, weight) {
+                break;
+            }
+            queue.pop_front();
+
+            let message = match message {
+                Ok(message) => message,
+                Err(err) => {
+                    // TODO: Fix reply code
+                    let code = if err.kind() == ErrorKind::InvalidData {
+                        ReplyCode::ERR_INPUTTOOLONG
+                    } else {
+                        ReplyCode::ERR_UNKNOWNCOMMAND
+                    };
+                    let response = Response::new(hostname, code, &[&err.to_string()]);
+                    send_to_user(&response, users, user_id)?;
+                    continue;
+                }
+            };
+
+            // A command that fails only affects that command, e.g. if a file couldn't be saved,
+            // so the connection carries on
+            let command = message.command.to_string().to_uppercase();
+            match handle_message(message, state, user_id) {
+                Ok(CommandResponse::Quit) => return Ok(()),
+                Ok(CommandResponse::Continue) => {}
+                Err(err) => println!("Failed to handle {command} from {user_id}: {err}"),
+            }
+        }
+
+        // Drop clients that send faster than they're allowed to, send more than their RecvQ holds,
+        // or stop answering PINGs
+        let received = buffer.len() + queue.iter().map(Vec::len).sum::<usize>();
+        let reason = if !is_exempt && queue.len() > limits.flood.max_queued {
+            Some("Excess Flood".to_string())
+        } else if received > limits.recvq {
+            Some("RecvQ exceeded".to_string())
+        } else if is_pinged && last_active.elapsed() >= limits.ping_frequency * 2 {
+            Some(format!(
+                "Ping timeout: {} seconds",
+                last_active.elapsed().as_secs()
+            ))
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return disconnect_user(state, user_id, &reason);
+        }
+
+        // Make sure an idle client is still there
+        if !is_pinged && last_active.elapsed() >= limits.ping_frequency {
+            let ping = Message::new(Some(hostname.to_string()), Command::Ping, &[hostname]);
+            send_to_user(&ping, users, user_id)?;
+            is_pinged = true;
+        }
+
+        // Wait for message from client, but only until the next queued line can be handled or the
+        // client is due a PING or has failed to answer one
+        let ping_deadline = limits.ping_frequency * if is_pinged { 2 } else { 1 };
+        let mut timeout = ping_deadline.saturating_sub(last_active.elapsed());
+        if let Some(line) = queue.front() {
+            let weight = Message::from(&decode_line(line))
+                .map_or(1.0, |message| flood::weight(&message.command));
+            timeout = timeout.min(bucket.time_until(&limits.flood, weight));
+        }
+        stream.set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
         let mut message_ascii = vec![0; shared::MESSAGE_SIZE];
-        stream
-            .read(&mut message_ascii)
-            .expect("Failed to read message from client.");
-
-        // Convert `message` to a String and print it out
-        let message_str = str::from_utf8(&message_ascii)
-            .expect("Client sent an invalid UTF-8 message.")
-            .replace('\0', "");
-        println!("{:?}", message_str);
-
-        // Extract IRC command from client input
-        let message = match Message::from(&message_str) {
-            Ok(message) => message,
+        let length = match stream.read(&mut message_ascii) {
+            // The client went away without a QUIT, e.g. because it crashed or lost its network
+            Ok(0) => return Err(ServerError::Disconnected),
+            Ok(length) => length,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => 0,
             Err(err) => {
-                // TODO: Fix reply code
-                let response =
-                    Response::new(hostname, ReplyCode::ERR_UNKNOWNCOMMAND, &[&err.to_string()]);
-                send_to_user(&response, &users, user_id).expect("Failed to send message.");
-                continue;
+                println!("Failed to read from {user_id}: {err}");
+                return Err(ServerError::Disconnected);
             }
         };
+        if length > 0 {
+            last_active = Instant::now();
+            is_pinged = false;
+        }
+        buffer.extend_from_slice(&message_ascii[..length]);
 
-        if handle_message(message, &users, &channels, user_id, hostname)
-            .expect("Failed to parse command.")
-            == CommandResponse::Quit
-        {
-            break;
+        // A single read can hold several messages, e.g. when a client sends CAP, NICK, and USER
+        // all at once, so queue each complete line separately
+        while let Some(end) = buffer.iter().position(|&byte| byte == b'\n') {
+            let line = buffer.drain(..=end).collect::<Vec<_>>();
+            if !line.iter().all(u8::is_ascii_whitespace) {
+                queue.push_back(line);
+            }
         }
     }
+}
 
-    // Remove user from the table
-    users
-        .lock()
-        .expect("Unable to get lock on users table.")
-        .remove(&user_id);
+/// Decode a line the client sent. Lines that aren't valid UTF-8 are most likely from an older
+/// client using Latin-1, which maps each byte straight to a character.
+fn decode_line(line: &[u8]) -> String {
+    match str::from_utf8(line) {
+        Ok(line) => line.to_string(),
+        Err(_) => line.iter().map(|&byte| char::from(byte)).collect(),
+    }
 }
 
-fn handle_message<'a>(
+/// Handle a message a client sent, as coming from the user and with only the tags they're allowed
+/// to send.
+pub fn handle_message(
     mut message: Message,
-    users: &'a UserTable,
-    channels: &'a ChannelTable,
+    state: &ServerState,
     user_id: Uuid,
-    server_prefix: &str,
-) -> Result<CommandResponse, Box<dyn std::error::Error + 'a>> {
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
     // Update message's prefix to the user's in case we need to broadcast this message to other
     // users
     message.prefix = users
-        .lock()
-        .expect("Unable to get a lock on the users table.")
+        .lock()?
         .get(&user_id)
-        .unwrap()
+        .ok_or(ServerError::NoSuchUser)?
         .prefix();
 
-    // In order for a user to become registered, the client has to send a NICK message with a valid
-    // nickname and a USER message with their username. If all checks pass, they will receieve a
-    // welcome message.
-
-    // Only allow USER, NICK, and QUIT commands if user is not registered
-    if !users.lock().unwrap().get(&user_id).unwrap().is_registered
-        && !matches!(
-            message.command,
-            Command::User | Command::Nick | Command::Quit
-        )
-    {
+    // Clients can only send tags of their own, which start with a +. Every other tag is up to the
+    // server.
+    if message.tags_length() > MAX_CLIENT_TAGS_LENGTH {
         let response = Response::new(
             server_prefix,
-            ReplyCode::ERR_NOTREGISTERED,
-            &["You have not registered."],
+            ReplyCode::ERR_INPUTTOOLONG,
+            &["Input line was too long."],
         );
-        send_to_user(&response, &users, user_id)?;
+        send_to_user(&response, users, user_id)?;
         return Ok(CommandResponse::Continue);
     }
+    let batch = message.tags.remove("batch");
+    let concat = message.tags.remove("draft/multiline-concat").is_some();
+    message.tags.retain(|key, _| key.starts_with('+'));
+    let (account, is_registered) = {
+        let lock = users.lock()?;
+        let user = lock.get(&user_id).ok_or(ServerError::NoSuchUser)?;
+        (user.account.clone(), user.is_registered)
+    };
+    if let Some(account) = account {
+        message.tags.insert("account".to_string(), account);
+    }
 
-    // Perform command associated with message
-    match message.command {
-        Command::User => {
-            // Example: USER guest 0 * :Ronnie Reagan
+    // Lines of a multi-line message are held until its batch ends. Anything else goes to the
+    // handler of its command.
+    match batch {
+        Some(reference) if is_registered => {
+            multiline::add_line(message, state, user_id, &reference, concat)
+        }
+        _ => state.handlers.dispatch(message, state, user_id),
+    }
+}
 
-            // We will only parse the first argument (username) and ignore the rest
-            let username = match message.params.get(0) {
-                Some(name) => name.clone(),
-                None => {
-                    let response = Response::new(
-                        server_prefix,
-                        ReplyCode::ERR_NONICKNAMEGIVEN,
-                        &["No nickname was given."],
-                    );
-                    send_to_user(&response, &users, user_id)?;
-                    // user.stream.write_all(response.to_irc().as_bytes())?;
+/// Return the handlers of every command clients can send, along with what they need to send them.
+/// In order for a user to become registered, the client has to send a NICK message with a valid
+/// nickname and a USER message with their username, so only a few commands work before then.
+pub fn handlers() -> Registry {
+    let mut registry = Registry::default();
+    let unregistered = Requirements::none();
+    let registered = Requirements::registered();
+    let oper = registered.oper();
 
-                    return Ok(CommandResponse::Continue);
-                }
-            };
+    registry.route_typed(Command::Pass, unregistered, handle_command);
+    registry.route_typed(Command::User, unregistered, handle_command);
+    registry.route_typed(Command::Nick, unregistered, handle_command);
+    registry.route_typed(Command::Ping, unregistered, handle_command);
+    registry.route_typed(Command::Pong, unregistered, handle_command);
+    registry.route_typed(Command::Quit, unregistered, handle_command);
+    registry.route(Command::Cap, unregistered.params(1), caps::handle_cap);
+    registry.route(
+        Command::Authenticate,
+        unregistered.params(1),
+        sasl::handle_authenticate,
+    );
 
-            let mut lock = users.lock().expect("Unable to get lock on users table.");
-            let mut user = lock.get_mut(&user_id).unwrap();
+    registry.route_typed(Command::Join, registered, handle_command);
+    registry.route_typed(Command::Part, registered, handle_command);
+    registry.route_typed(Command::PrivMsg, registered, handle_command);
+    registry.route_typed(Command::Notice, registered, handle_command);
+    registry.route_typed(Command::TagMsg, registered, handle_command);
+    registry.route_typed(Command::Away, registered, handle_command);
+    registry.route(
+        Command::Register,
+        registered.params(3),
+        registration::handle_register,
+    );
+    registry.route(Command::Mode, registered.params(1), modes::handle_mode);
+    registry.route(Command::Names, registered, query::handle_names);
+    registry.route(Command::Who, registered, query::handle_who);
+    registry.route(
+        Command::Invite,
+        registered.params(2),
+        channels::handle_invite,
+    );
+    registry.route(Command::Topic, registered.params(1), channels::handle_topic);
+    registry.route(
+        Command::Cregister,
+        registered.params(1),
+        channels::handle_cregister,
+    );
+    registry.route(
+        Command::Access,
+        registered.params(2),
+        channels::handle_access,
+    );
+    registry.route(
+        Command::ChatHistory,
+        registered.capability("draft/chathistory"),
+        history::handle_chathistory,
+    );
+    registry.route(
+        Command::Redact,
+        registered.params(2).capability("draft/message-redaction"),
+        history::handle_redact,
+    );
+    registry.route(
+        Command::Batch,
+        registered.params(1),
+        multiline::handle_batch,
+    );
+    registry.route(
+        Command::Monitor,
+        registered.params(1),
+        monitor::handle_monitor,
+    );
+    registry.route(Command::Stats, registered.params(1), oper::handle_stats);
+    registry.route(Command::Oper, registered.params(2), oper::handle_oper);
 
-            // If the user is already registered, ignore the request and send ERR_ALREADYREGISTERED
-            if user.is_registered {
-                drop(lock);
-                let response = Response::new(
-                    server_prefix,
-                    ReplyCode::ERR_ALREADYREGISTRED,
-                    &["Cannot send USER message since the client is already registered."],
-                );
+    registry.route(Command::Kill, oper.params(1), oper::handle_kill);
+    registry.route(Command::Wallops, oper.params(1), oper::handle_wallops);
+    registry.route(Command::Rehash, oper, |_, state, user_id| {
+        oper::handle_rehash(state, user_id)
+    });
+    registry.route(Command::Die, oper, oper::handle_die);
+    registry.route(Command::Restart, oper, oper::handle_die);
+    // The mask of a ban comes after an optional duration, which the handler checks for
+    registry.route(Command::Kline, oper, oper::handle_ban);
+    registry.route(Command::Dline, oper, oper::handle_ban);
+    registry.route(Command::Unkline, oper.params(1), oper::handle_unban);
+    registry.route(Command::Undline, oper.params(1), oper::handle_unban);
 
-                // Send response to client
-                // user.stream.write_all(response.to_irc().as_bytes())?;
+    registry
+}
 
-                send_to_user(&response, &users, user_id)?;
-                return Ok(CommandResponse::Continue);
-            }
+/// Handle the commands that have a typed form.
+fn handle_command(
+    message: Message,
+    command: ClientCommand,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
 
-            user.username = Some(username);
+    match command {
+        ClientCommand::Pass { password } => handle_pass(password, users, user_id, server_prefix),
+        ClientCommand::User { username, realname } => {
+            handle_user(username, realname, state, user_id)
         }
-        Command::Nick => {
-            // Example: NICK Wiz
-
-            // Get the first parameter in the message
-            let nickname = match message.params.get(0) {
-                Some(name) => name.clone(),
-                None => {
-                    let response = Response::new(
-                        server_prefix,
-                        ReplyCode::ERR_NONICKNAMEGIVEN,
-                        &["No nickname was given."],
-                    );
-                    // user.stream.write_all(response.to_irc().as_bytes())?;
-                    send_to_user(&response, &users, user_id)?;
-                    return Ok(CommandResponse::Continue);
-                }
-            };
-
-            if nickname_in_use(&nickname, &users) {
-                let response = Response::new(
-                    server_prefix,
-                    ReplyCode::ERR_NICKNAMEINUSE,
-                    &["Nickname is already in use."],
-                );
-                // user.stream.write_all(response.to_irc().as_bytes())?;
-                send_to_user(&response, &users, user_id)?;
-                return Ok(CommandResponse::Continue);
-            }
-
-            // Update nickname and get the registration state of the user
-            let is_registered = {
-                let mut lock = users.lock().expect("Unable to get lock on users table.");
-                let mut user = lock.get_mut(&user_id).unwrap();
-                user.nickname = Some(nickname);
-                user.is_registered
-            };
-
-            // Only broadcast NICK message if user is registered
-            if is_registered {
-                broadcast_to_all(&message, &users)?;
-            }
+        ClientCommand::Nick { nickname } => handle_nick(message, nickname, state, user_id),
+        ClientCommand::Join { channels, keys } => {
+            channels::handle_join(message, channels, keys, state, user_id)
         }
-        Command::Away => {
-            let mut lock = users.lock().expect("Unable to get lock on users table.");
-            let mut user = lock.get_mut(&user_id).unwrap();
-
-            // Toggle away status
-            let away = !user.is_away;
-            user.is_away = away;
-            drop(lock);
+        ClientCommand::Part { channels, reason } => {
+            channels::handle_part(channels, reason, state, user_id)
+        }
+        ClientCommand::PrivMsg { targets, .. } | ClientCommand::Notice { targets, .. } => {
+            handle_privmsg(message, targets, state, user_id)
+        }
+        ClientCommand::TagMsg { targets } => handle_tagmsg(message, targets, state, user_id),
+        ClientCommand::Away { text } => {
+            // AWAY with a message marks the user as away, and without one marks them as back
+            let away = text.is_some();
+            users
+                .lock()?
+                .get_mut(&user_id)
+                .ok_or(ServerError::NoSuchUser)?
+                .is_away = away;
 
             let response = if away {
                 Response::new(
@@ -227,209 +473,243 @@ fn handle_message<'a>(
                     &["You are no longer away."],
                 )
             };
-            send_to_user(&response, &users, user_id)?;
+            send_to_user(&response, users, user_id)?;
+            Ok(CommandResponse::Continue)
         }
-        Command::PrivMsg => {
-            // Example: PRIVMSG user :Hello there!
-            //          PRIVMSG #channel :Hello there!
-            if message.params.len() != 2 {
-                let response = Response::new(
-                    server_prefix,
-                    ReplyCode::ERR_NORECIPIENT,
-                    &["No recipient for the message was given."],
-                );
-                send_to_user(&response, &users, user_id)?;
-                return Ok(CommandResponse::Continue);
-            }
-
-            let recipient = message.params.get(0).unwrap().clone();
-
-            // It's not a channel
-            if !recipient.starts_with("#") {
-                if let Some(nickname_id) = get_nickname_id(&recipient, &users) {
-                    let is_away = users
-                        .lock()
-                        .expect("Unable to get lock on users table.")
-                        .get(&nickname_id)
-                        .unwrap()
-                        .is_away;
-                    if is_away {
-                        let response = Response::new(
-                            server_prefix,
-                            ReplyCode::RPL_AWAY,
-                            &[&recipient, "The recipient is marked as away."],
-                        );
-                        send_to_user(&response, &users, user_id)?;
-                    }
-
-                    send_to_user(&message, &users, nickname_id)?;
-                } else {
-                    let response = Response::new(
-                        server_prefix,
-                        ReplyCode::ERR_NOSUCHNICK,
-                        &["The given nick was not found."],
-                    );
-                    send_to_user(&response, &users, user_id)?;
-                }
-            } else {
-                if let Some(channel) = channels.lock().unwrap().get(&recipient) {
-                    send_to_channel(&message, &users, channel)?;
-                } else {
-                    let response = Response::new(
-                        server_prefix,
-                        ReplyCode::ERR_NOSUCHCHANNEL,
-                        &["The given channel was not found."],
-                    );
-                    send_to_user(&response, &users, user_id)?;
-                }
-            }
+        ClientCommand::Ping { token } => {
+            let pong = Message::new(
+                Some(server_prefix.to_string()),
+                Command::Pong,
+                &[server_prefix, &token],
+            );
+            send_to_user(&pong, users, user_id)?;
+            Ok(CommandResponse::Continue)
         }
-        Command::Quit => {
+        // Answering a PING only has to show the client is still there, which receiving anything
+        // already does
+        ClientCommand::Pong { .. } => Ok(CommandResponse::Continue),
+        ClientCommand::Quit { reason } => {
             let acknowledgement_response = Message::new(
                 Some(server_prefix.to_string()),
                 Command::Error,
                 &["User disconnected."],
             );
-            send_to_user(&acknowledgement_response, &users, user_id)?;
-
-            // If the user is registered, tell everyone else that the user has left.
-            let is_registered = users
-                .lock()
-                .expect("Unable to get lock on users table.")
-                .get(&user_id)
-                .unwrap()
-                .is_registered;
-            if is_registered {
-                broadcast_message(&message, &users, user_id)?;
-            }
+            send_to_user(&acknowledgement_response, users, user_id)?;
 
-            return Ok(CommandResponse::Quit);
+            remove_user(state, user_id, reason.as_deref().unwrap_or("Client Quit"))?;
+            Ok(CommandResponse::Quit)
         }
-        Command::Unknown => {
+        // Commands without a typed form have handlers of their own, so one only gets here if it
+        // was routed to this handler by mistake
+        ClientCommand::Other(_) => {
             let response = Response::new(
                 server_prefix,
                 ReplyCode::ERR_UNKNOWNCOMMAND,
                 &["Unknown command."],
             );
-            send_to_user(&response, &users, user_id)?;
+            send_to_user(&response, users, user_id)?;
+            Ok(CommandResponse::Continue)
         }
-        Command::Join => {
-            let channel_name = match message.params.get(0) {
-                Some(name) => name.clone(),
-                None => {
+    }
+}
+
+fn handle_privmsg(
+    message: Message,
+    targets: Vec<String>,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: PRIVMSG user :Hello there!
+    //          NOTICE #channel,bob :Hello there!
+
+    // NOTICEs never get an error back, so that automatic replies can't loop
+    let is_notice = matches!(message.command, Command::Notice);
+    for recipient in targets {
+        // Each target gets the message addressed to them alone
+        let mut message = message.clone();
+        message.params[0] = recipient.clone();
+        stamp_message(&mut message);
+
+        // The channel is cloned out so the channels lock isn't held while the message is relayed
+        let channel = state.channels.lock().unwrap().get(&recipient).cloned();
+
+        // It's not a channel
+        let error = if !recipient.starts_with('#') {
+            // The recipient may have left since their nickname was looked up
+            let found = match get_nickname_id(&recipient, users)? {
+                Some(nickname_id) => users
+                    .lock()?
+                    .get(&nickname_id)
+                    .map(|target| (nickname_id, target.is_away)),
+                None => None,
+            };
+            if let Some((nickname_id, is_away)) = found {
+                if is_away && !is_notice {
                     let response = Response::new(
                         server_prefix,
-                        ReplyCode::ERR_NEEDMOREPARAMS,
-                        &["Specify which channel to join."],
+                        ReplyCode::RPL_AWAY,
+                        &[&recipient, "The recipient is marked as away."],
                     );
-                    send_to_user(&response, &users, user_id)?;
-                    return Ok(CommandResponse::Continue);
+                    send_to_user(&response, users, user_id)?;
                 }
-            };
 
-            // Get a reference to the channel if it is in the channels table, otherwise create it
-            let channel = channels
-                .lock()
-                .unwrap()
-                .entry(channel_name.clone())
-                .or_insert(Arc::new(Channel::new(&channel_name)))
-                .clone();
-
-            // Set the user's channel to the channel from the table
-            users.lock().unwrap().get_mut(&user_id).unwrap().channel = Some(channel);
-            // TODO: Broadcast
-        }
-        // Command::Kick => todo!(),
-        Command::Part => {
-            let channel_name = match message.params.get(0) {
-                Some(name) => name.clone(),
+                send_to_user(&message, users, nickname_id)?;
+                echo_message(&message, users, user_id)?;
+                if let Some(key) = history::users_direct_key(users, user_id, nickname_id)? {
+                    state.history.lock().unwrap().add(&key, message);
+                }
+                None
+            } else {
+                Some(Response::new(
+                    server_prefix,
+                    ReplyCode::ERR_NOSUCHNICK,
+                    &["The given nick was not found."],
+                ))
+            }
+        } else if let Some(channel) = channel {
+            send_to_channel_except(&message, users, &channel, user_id)?;
+            echo_message(&message, users, user_id)?;
+            let key = history::channel_key(&channel.name);
+            state.history.lock().unwrap().add(&key, message);
+            None
+        } else {
+            Some(Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOSUCHCHANNEL,
+                &["The given channel was not found."],
+            ))
+        };
+
+        if let Some(response) = error {
+            if !is_notice {
+                send_to_user(&response, users, user_id)?;
+            }
+        }
+    }
+
+    Ok(CommandResponse::Continue)
+}
+
+fn handle_tagmsg(
+    message: Message,
+    targets: Vec<String>,
+    state: &ServerState,
+    user_id: Uuid,
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    // Example: @+typing=active TAGMSG #rust
+    //          @+draft/react=👍;+draft/reply=abc TAGMSG bob
+
+    // Typing notifications are sent often and don't matter much, so any over the limit are
+    // dropped instead of slowing the user down
+    let rate_limit = state.config.read().unwrap().tagmsg_rate_limit;
+    let is_limited = {
+        let mut lock = users.lock()?;
+        let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+        let now = Instant::now();
+        if now.duration_since(user.tagmsg_window.0) >= Duration::from_secs(1) {
+            user.tagmsg_window = (now, 0);
+        }
+        user.tagmsg_window.1 += 1;
+        user.tagmsg_window.1 > rate_limit
+    };
+    if is_limited {
+        return Ok(CommandResponse::Continue);
+    }
+
+    for target in targets {
+        let channel = if target.starts_with('#') {
+            match state.channels.lock().unwrap().get(&target) {
+                Some(channel) => Some(channel.clone()),
                 None => {
                     let response = Response::new(
                         server_prefix,
-                        ReplyCode::ERR_NEEDMOREPARAMS,
-                        &["Specify which channel to join."],
+                        ReplyCode::ERR_NOSUCHCHANNEL,
+                        &["The given channel was not found."],
                     );
-                    send_to_user(&response, &users, user_id)?;
-                    return Ok(CommandResponse::Continue);
+                    send_to_user(&response, users, user_id)?;
+                    continue;
                 }
-            };
+            }
+        } else if get_nickname_id(&target, users)?.is_some() {
+            None
+        } else {
+            let response = Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOSUCHNICK,
+                &["The given nick was not found."],
+            );
+            send_to_user(&response, users, user_id)?;
+            continue;
+        };
 
-            let exists = channels.lock().unwrap().get(&channel_name).is_some();
-            if exists {
-                users.lock().unwrap().get_mut(&user_id).unwrap().channel = None;
-                // TODO: Broadcast
+        // A TAGMSG is nothing but its tags, so it only goes to clients that understand them
+        let mut message = message.clone();
+        message.params = vec![target.clone()];
+        stamp_message(&mut message);
+        let mut lock = users.lock()?;
+        for user in lock.values_mut() {
+            let is_recipient = if user.id == user_id {
+                user.capabilities.contains("echo-message")
             } else {
-                let response = Response::new(
-                    server_prefix,
-                    ReplyCode::ERR_NOSUCHCHANNEL,
-                    &["The given channel was not found."],
-                );
-                send_to_user(&response, &users, user_id)?;
+                match &channel {
+                    Some(channel) => user.channel.as_ref() == Some(channel),
+                    None => user.nickname.as_ref() == Some(&target),
+                }
+            };
+            if is_recipient && user.capabilities.contains("message-tags") {
+                user.send(&message)?;
             }
         }
-        Command::List => todo!(),
-        _ => {
-            // let response = Response {
-            //     prefix: server_prefix.to_string(),
-            //     code: ReplyCode::RPL_WELCOME,
-            //     params: vec!["Welcome to the Internet Relay Network!".to_string()],
-            // };
-            // user.stream.write_all(response.to_irc().as_bytes())?;
-            send_to_user(&message, &users, user_id)?;
-        }
     }
 
-    let mut lock = users.lock().expect("Unable to get lock on users table.");
-    let mut user = lock.get_mut(&user_id).unwrap();
+    Ok(CommandResponse::Continue)
+}
+
+fn handle_pass(
+    password: String,
+    users: &UserTable,
+    user_id: Uuid,
+    server_prefix: &str,
+) -> Result<CommandResponse, ServerError> {
+    // Example: PASS secretpasswordhere
+    let mut lock = users.lock()?;
+    let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
 
-    // Send welcome message if user is now registered
-    if !user.is_registered && user.prefix() != None {
-        user.is_registered = true;
+    // The password can only be given before registering
+    if user.is_registered {
+        drop(lock);
         let response = Response::new(
-            &user.prefix().unwrap(),
-            ReplyCode::RPL_WELCOME,
-            &[
-                user.nickname.as_ref().unwrap(),
-                &format!(
-                    "Welcome to the Internet Relay Network {}",
-                    user.prefix().unwrap()
-                ),
-            ],
+            server_prefix,
+            ReplyCode::ERR_ALREADYREGISTRED,
+            &["Cannot send PASS message since the client is already registered."],
         );
-        user.stream.write_all(response.to_irc().as_bytes())?;
+        send_to_user(&response, users, user_id)?;
+        return Ok(CommandResponse::Continue);
     }
 
-    drop(lock);
-
+    user.password = Some(password);
     Ok(CommandResponse::Continue)
 }
 
-fn handle_user<'a>(
-    message: Message,
-    users: &'a UserTable,
+fn handle_user(
+    username: String,
+    realname: String,
+    state: &ServerState,
     user_id: Uuid,
-    server_prefix: &str,
-) -> Result<CommandResponse, Box<dyn std::error::Error + 'a>> {
-    // Example: USER guest 0 * :Ronnie Reagan
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
 
-    // We will only parse the first argument (username) and ignore the rest
-    let username = match message.params.get(0) {
-        Some(name) => name.clone(),
-        None => {
-            let response = Response::new(
-                server_prefix,
-                ReplyCode::ERR_NONICKNAMEGIVEN,
-                &["No nickname was given."],
-            );
-            send_to_user(&response, &users, user_id)?;
-            // user.stream.write_all(response.to_irc().as_bytes())?;
-
-            return Ok(CommandResponse::Continue);
-        }
-    };
+    // Example: USER guest 0 * :Ronnie Reagan
 
-    let mut lock = users.lock().expect("Unable to get lock on users table.");
-    let mut user = lock.get_mut(&user_id).unwrap();
+    let mut lock = users.lock()?;
+    let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
 
     // If the user is already registered, ignore the request and send ERR_ALREADYREGISTERED
     if user.is_registered {
@@ -439,134 +719,558 @@ fn handle_user<'a>(
             ReplyCode::ERR_ALREADYREGISTRED,
             &["Cannot send USER message since the client is already registered."],
         );
-
-        // Send response to client
-        // user.stream.write_all(response.to_irc().as_bytes())?;
-
-        send_to_user(&response, &users, user_id)?;
+        send_to_user(&response, users, user_id)?;
         return Ok(CommandResponse::Continue);
     }
 
     user.username = Some(username);
-    return Ok(CommandResponse::Continue);
+    user.realname = Some(realname);
+    drop(lock);
+
+    try_register(state, user_id)
 }
 
-fn handle_nick<'a>(
+fn handle_nick(
     message: Message,
-    users: &'a UserTable,
+    nickname: String,
+    state: &ServerState,
     user_id: Uuid,
-    server_prefix: &str,
-) -> Result<CommandResponse, Box<dyn std::error::Error + 'a>> {
-    // Example: NICK Wiz
+) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
 
-    // Get the first parameter in the message
-    let nickname = match message.params.get(0) {
-        Some(name) => name.clone(),
-        None => {
-            let response = Response::new(
-                server_prefix,
-                ReplyCode::ERR_NONICKNAMEGIVEN,
-                &["No nickname was given."],
-            );
-            // user.stream.write_all(response.to_irc().as_bytes())?;
-            send_to_user(&response, &users, user_id)?;
-            return Ok(CommandResponse::Continue);
-        }
-    };
+    // Example: NICK Wiz
 
-    if nickname_in_use(&nickname, &users) {
+    if nickname_in_use(&nickname, users)? {
         let response = Response::new(
             server_prefix,
             ReplyCode::ERR_NICKNAMEINUSE,
             &["Nickname is already in use."],
         );
-        // user.stream.write_all(response.to_irc().as_bytes())?;
-        send_to_user(&response, &users, user_id)?;
+        send_to_user(&response, users, user_id)?;
         return Ok(CommandResponse::Continue);
     }
 
-    let mut lock = users.lock().expect("Unable to get lock on users table.");
-    let mut user = lock.get_mut(&user_id).unwrap();
-    user.nickname = Some(nickname);
+    let mut lock = users.lock()?;
+    let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+    let old_nickname = user.nickname.replace(nickname);
+    let prefix = user.prefix();
     let is_registered = user.is_registered;
     drop(lock);
 
     // Only broadcast NICK message if user is registered
     if is_registered {
-        broadcast_to_all(&message, &users)?;
-        // broadcast_message(&message, users);
+        broadcast_to_all(&message, users)?;
+        monitor::notify_offline(state, &old_nickname.unwrap())?;
+        monitor::notify_online(state, &prefix.unwrap())?;
+        registration::check_nickname(state, user_id)?;
+        return Ok(CommandResponse::Continue);
     }
-    return Ok(CommandResponse::Continue);
+
+    try_register(state, user_id)
 }
 
-pub fn send_to_user<'a, T: ToIrc>(
-    message: &T,
-    users: &'a UserTable,
-    id: Uuid,
-) -> Result<(), Box<dyn std::error::Error + 'a>> {
-    Ok(users
+/// Finish registering the user once they have given both a nickname and a username. If the
+/// connection requires a password, it is checked before the user is welcomed, and the connection is
+/// closed if it doesn't match. Registration waits for CAP END if the client started negotiating
+/// capabilities.
+pub fn try_register(state: &ServerState, user_id: Uuid) -> Result<CommandResponse, ServerError> {
+    let users = &state.users;
+    let server_prefix = &state.server_name();
+
+    let mut lock = users.lock()?;
+    let user = lock.get_mut(&user_id).ok_or(ServerError::NoSuchUser)?;
+
+    let prefix = match user.prefix() {
+        Some(prefix) if !user.is_registered && !user.cap_negotiating => prefix,
+        _ => return Ok(CommandResponse::Continue),
+    };
+
+    let config = state.config.read().unwrap();
+    let required_password = config.required_password(&user.listener, user.class.as_deref());
+    if required_password.is_some() && required_password != user.password.as_deref() {
+        drop(lock);
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::ERR_PASSWDMISMATCH,
+            &["Password incorrect."],
+        );
+        send_to_user(&response, users, user_id)?;
+        let error = Message::new(
+            Some(server_prefix.to_string()),
+            Command::Error,
+            &["Closing link: Bad password."],
+        );
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Quit);
+    }
+
+    let user_host = format!("{}@{}", user.username.as_ref().unwrap(), user.hostname);
+    let kline = state.bans.lock().unwrap().find_kline(&user_host).cloned();
+    if let Some(ban) = kline {
+        drop(lock);
+        let response = Response::new(
+            server_prefix,
+            ReplyCode::ERR_YOUREBANNEDCREEP,
+            &["You are banned from this server."],
+        );
+        send_to_user(&response, users, user_id)?;
+        let error = Message::new(
+            Some(server_prefix.to_string()),
+            Command::Error,
+            &[&format!("Closing link: K-line ({})", ban.reason)],
+        );
+        send_to_user(&error, users, user_id)?;
+        return Ok(CommandResponse::Quit);
+    }
+
+    // Send welcome message now that the user is registered
+    user.is_registered = true;
+    let response = Response::new(
+        server_prefix,
+        ReplyCode::RPL_WELCOME,
+        &[
+            user.nickname.as_ref().unwrap(),
+            &format!("Welcome to the Internet Relay Network {}", prefix),
+        ],
+    );
+    user.send(&response)?;
+    let response = Response::new(
+        server_prefix,
+        ReplyCode::RPL_ISUPPORT,
+        &[
+            user.nickname.as_ref().unwrap(),
+            &format!("MONITOR={}", config.monitor_limit),
+            "are supported by this server",
+        ],
+    );
+    user.send(&response)?;
+    drop(config);
+    drop(lock);
+
+    monitor::notify_online(state, &prefix)?;
+    registration::check_nickname(state, user_id)?;
+
+    Ok(CommandResponse::Continue)
+}
+
+/// Disconnect a user, telling them why with an ERROR and the users they share a channel with with
+/// a QUIT.
+pub fn disconnect_user(
+    state: &ServerState,
+    user_id: Uuid,
+    reason: &str,
+) -> Result<(), ServerError> {
+    let error = Message::new(
+        Some(state.server_name()),
+        Command::Error,
+        &[&format!("Closing link: {}", reason)],
+    );
+    send_to_user(&error, &state.users, user_id)?;
+    remove_user(state, user_id, reason)
+}
+
+/// Remove a user whose connection is ending, telling the users they share a channel with that they
+/// left with a QUIT. Dropping the user closes their connection once everything sent to them has
+/// been written, which makes their connection thread stop. Does nothing if the user is already
+/// gone.
+pub fn remove_user(state: &ServerState, user_id: Uuid, reason: &str) -> Result<(), ServerError> {
+    let user = match state.users.lock()?.remove(&user_id) {
+        Some(user) => user,
+        None => return Ok(()),
+    };
+    if let Some(channel) = &user.channel {
+        channel.operators.lock()?.remove(&user_id);
+    }
+    state.remove_empty_channels()?;
+
+    if let (true, Some(nickname)) = (user.is_registered, &user.nickname) {
+        if let Some(channel) = &user.channel {
+            let mut quit = Message::new(user.prefix(), Command::Quit, &[reason]);
+            stamp_message(&mut quit);
+            send_to_channel(&quit, &state.users, channel)?;
+        }
+        monitor::notify_offline(state, nickname)?;
+    }
+
+    Ok(())
+}
+
+/// Tag a message the server relays with the time it was received and a unique ID. Clients only
+/// see the tags if they negotiated `server-time` or `message-tags`.
+pub fn stamp_message(message: &mut Message) {
+    let time = Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string();
+    message.tags.insert("time".to_string(), time);
+    message
+        .tags
+        .insert("msgid".to_string(), Uuid::new_v4().to_simple().to_string());
+}
+
+/// Send the user a NOTICE from the server.
+pub fn send_notice(state: &ServerState, user_id: Uuid, text: &str) -> Result<(), ServerError> {
+    let nickname = state
+        .users
         .lock()?
-        .get_mut(&id)
-        .unwrap()
-        .stream
-        .write_all(message.to_irc().as_bytes())?)
+        .get(&user_id)
+        .ok_or(ServerError::NoSuchUser)?
+        .nickname
+        .clone()
+        .unwrap();
+    let notice = Message::new(
+        Some(state.server_name()),
+        Command::Notice,
+        &[&nickname, text],
+    );
+    send_to_user(&notice, &state.users, user_id)
 }
 
-pub fn send_to_channel<'a, T: ToIrc>(
+pub fn send_to_user<T: ToIrc>(message: &T, users: &UserTable, id: Uuid) -> Result<(), ServerError> {
+    // The user may have left while the message was being put together
+    match users.lock()?.get_mut(&id) {
+        Some(user) => Ok(user.send(message)?),
+        None => Ok(()),
+    }
+}
+
+pub fn send_to_channel<T: ToIrc>(
     message: &T,
-    users: &'a UserTable,
+    users: &UserTable,
     channel: &Arc<Channel>,
-) -> Result<(), Box<dyn std::error::Error + 'a>> {
-    Ok(users
+) -> Result<(), ServerError> {
+    users
         .lock()?
         .iter_mut()
         .filter(|(_, user)| user.channel == Some(channel.clone()))
-        .for_each(|(_, user)| user.stream.write_all(message.to_irc().as_bytes()).unwrap()))
+        .for_each(|(_, user)| send_ignoring_errors(user, message));
+    Ok(())
 }
 
-pub fn broadcast_message<'a, T: ToIrc>(
+/// Send a message to everyone in a channel except one user, usually the one who sent it.
+pub fn send_to_channel_except<T: ToIrc>(
     message: &T,
-    users: &'a UserTable,
+    users: &UserTable,
+    channel: &Arc<Channel>,
     id_to_exclude: Uuid,
-) -> Result<(), Box<dyn std::error::Error + 'a>> {
-    Ok(users
+) -> Result<(), ServerError> {
+    users
         .lock()?
-        // .unwrap()
         .iter_mut()
-        .filter(|(id, _)| **id != id_to_exclude)
-        .for_each(|(_, user)| user.stream.write_all(message.to_irc().as_bytes()).unwrap()))
+        .filter(|(id, user)| **id != id_to_exclude && user.channel == Some(channel.clone()))
+        .for_each(|(_, user)| send_ignoring_errors(user, message));
+    Ok(())
 }
 
-pub fn broadcast_to_all<'a, T: ToIrc>(
-    message: &T,
-    users: &'a UserTable,
-) -> Result<(), Box<dyn std::error::Error + 'a>> {
-    Ok(users
+/// Send a user a copy of the message they sent, exactly as it was relayed, if they negotiated
+/// `echo-message`.
+pub fn echo_message(message: &Message, users: &UserTable, id: Uuid) -> Result<(), ServerError> {
+    let mut lock = users.lock()?;
+    match lock.get_mut(&id) {
+        Some(user) if user.capabilities.contains("echo-message") => Ok(user.send(message)?),
+        _ => Ok(()),
+    }
+}
+
+pub fn broadcast_to_all<T: ToIrc>(message: &T, users: &UserTable) -> Result<(), ServerError> {
+    users
         .lock()?
         .iter_mut()
-        .for_each(|(_, user)| user.stream.write_all(message.to_irc().as_bytes()).unwrap()))
+        .for_each(|(_, user)| send_ignoring_errors(user, message));
+    Ok(())
 }
 
-pub fn nickname_in_use(nickname: &str, users: &UserTable) -> bool {
-    for (_, user) in users.lock().unwrap().iter() {
+/// Send a message to one of many recipients. A recipient whose connection is broken is cleaned up by
+/// their own connection thread, so it shouldn't stop the message from reaching everyone else.
+fn send_ignoring_errors<T: ToIrc>(user: &mut User, message: &T) {
+    if let Err(err) = user.send(message) {
+        println!("Failed to send a message to {}: {}", user.hostname, err);
+    }
+}
+
+pub fn nickname_in_use(nickname: &str, users: &UserTable) -> Result<bool, ServerError> {
+    for (_, user) in users.lock()?.iter() {
         if let Some(name) = &user.nickname {
             if name == nickname {
-                return true;
+                return Ok(true);
             }
         }
     }
 
-    return false;
+    Ok(false)
 }
 
-pub fn get_nickname_id(nickname: &str, users: &UserTable) -> Option<Uuid> {
-    for (id, user) in users.lock().unwrap().iter() {
+pub fn get_nickname_id(nickname: &str, users: &UserTable) -> Result<Option<Uuid>, ServerError> {
+    for (id, user) in users.lock()?.iter() {
         if let Some(name) = &user.nickname {
             if name == nickname {
-                return Some(*id);
+                return Ok(Some(*id));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, state::tests as state};
+
+    /// Send `lines` as a new user to a server requiring the password "secret", returning what
+    /// handling the last one asked for and the replies the user was sent.
+    fn register(lines: &[&str]) -> (CommandResponse, Vec<String>) {
+        let state = state::server(Config {
+            password: Some("secret".to_string()),
+            ..Config::default()
+        });
+        let (user_id, client) = state::connect(&state);
+        let mut response = CommandResponse::Continue;
+        for line in lines {
+            let message = Message::from(line).unwrap();
+            response = handle_message(message, &state, user_id).unwrap();
+        }
+        (response, state::disconnect(&state, user_id, client))
+    }
+
+    #[test]
+    fn wrong_or_missing_password_closes_connection() {
+        for lines in [
+            &["PASS wrong", "NICK alice", "USER alice 0 * :Alice"][..],
+            &["NICK alice", "USER alice 0 * :Alice"],
+        ] {
+            let (response, replies) = register(lines);
+            assert_eq!(response, CommandResponse::Quit);
+            assert_eq!(replies[0], ":127.0.0.1 464 :Password incorrect.");
+            assert_eq!(replies[1], ":127.0.0.1 ERROR :Closing link: Bad password.");
+        }
+
+        let (response, replies) = register(&["PASS secret", "NICK alice", "USER alice 0 * :A"]);
+        assert_eq!(response, CommandResponse::Continue);
+        assert!(replies[0].starts_with(":127.0.0.1 001 alice "));
+    }
+
+    #[test]
+    fn away_with_a_message_sets_away_and_without_clears_it() {
+        let state = state::server(Config::default());
+        let (user_id, client) = state::register(&state, "alice");
+        let away = |line: &str| {
+            handle_message(Message::from(line).unwrap(), &state, user_id).unwrap();
+            state.users.lock().unwrap()[&user_id].is_away
+        };
+
+        assert!(away("AWAY :Gone fishing"));
+        assert!(away("AWAY :Still fishing"));
+        assert!(!away("AWAY"));
+        assert!(!away("AWAY"));
+
+        let codes = state::disconnect(&state, user_id, client)
+            .iter()
+            .map(|line| line.split(' ').nth(1).unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(codes, ["306", "306", "305", "305"]);
+    }
+
+    #[test]
+    fn untyped_commands_routed_to_typed_handler_are_unknown() {
+        let state = state::server(Config::default());
+        let (user_id, client) = state::register(&state, "alice");
+        let message = Message::from("LIST").unwrap();
+        let command = ClientCommand::Other(message.clone());
+        let response = handle_command(message, command, &state, user_id).unwrap();
+        assert_eq!(response, CommandResponse::Continue);
+        assert_eq!(
+            state::disconnect(&state, user_id, client),
+            [":127.0.0.1 421 :Unknown command."]
+        );
+    }
+
+    #[test]
+    fn relayed_messages_are_stamped_with_time_and_msgid() {
+        let state = state::server(Config::default());
+        let (alice, alice_client) = state::register(&state, "alice");
+        let (bob, bob_client) = state::register(&state, "bob");
+        let send = |line: &str, user_id| {
+            handle_message(Message::from(line).unwrap(), &state, user_id).unwrap();
+        };
+        let mut users = state.users.lock().unwrap();
+        let user = users.get_mut(&alice).unwrap();
+        user.capabilities.insert("message-tags".to_string());
+        drop(users);
+
+        send("JOIN #rust", alice);
+        send("JOIN #rust", bob);
+        send(
+            "@time=2000-01-01T00:00:00.000Z;msgid=forged;+draft/react=1 PRIVMSG #rust :Hi",
+            bob,
+        );
+        send("PART #rust", bob);
+        send("JOIN #rust", bob);
+        send("QUIT :Bye", bob);
+        state::disconnect(&state, bob, bob_client);
+
+        // Only keep what was relayed to alice from bob
+        let lines = state::disconnect(&state, alice, alice_client);
+        let relayed = lines
+            .iter()
+            .filter(|line| line.contains(" :bob!bob@127.0.0.1 "))
+            .collect::<Vec<_>>();
+        let commands = relayed
+            .iter()
+            .map(|line| line.split(' ').nth(2).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(commands, ["JOIN", "PRIVMSG", "PART", "JOIN", "QUIT"]);
+        let relayed = relayed
+            .iter()
+            .map(|line| Message::from(line).unwrap())
+            .collect::<Vec<_>>();
+        for message in &relayed {
+            assert!(message.tags["time"].starts_with(&Utc::now().format("%Y-").to_string()));
+            assert_eq!(message.tags["msgid"].len(), 32);
+        }
+        assert_eq!(
+            relayed[1].tags.get("+draft/react").map(String::as_str),
+            Some("1")
+        );
+    }
+
+    #[test]
+    fn echo_message_returns_what_recipients_saw() {
+        let state = state::server(Config::default());
+        let (alice, alice_client) = state::register(&state, "alice");
+        let (bob, bob_client) = state::register(&state, "bob");
+        let (carol, carol_client) = state::register(&state, "carol");
+        let mut users = state.users.lock().unwrap();
+        for (user_id, capabilities) in [
+            (alice, &["echo-message", "message-tags"][..]),
+            (bob, &["message-tags"]),
+            (carol, &["message-tags"]),
+        ] {
+            let user = users.get_mut(&user_id).unwrap();
+            user.capabilities
+                .extend(capabilities.iter().map(|capability| capability.to_string()));
+        }
+        drop(users);
+
+        for user_id in [alice, carol] {
+            for line in ["PRIVMSG bob :Hi", "NOTICE bob :Hello"] {
+                handle_message(Message::from(line).unwrap(), &state, user_id).unwrap();
             }
         }
+
+        let received = state::disconnect(&state, bob, bob_client);
+        assert_eq!(received.len(), 4);
+        // The echoes are the same lines bob got, msgid and all
+        assert!(received[0].contains("msgid="));
+        let echoed = state::disconnect(&state, alice, alice_client);
+        assert_eq!(echoed, received[..2]);
+        assert!(state::disconnect(&state, carol, carol_client).is_empty());
+    }
+
+    #[test]
+    fn tagmsg_bursts_are_dropped_past_the_rate_limit() {
+        let state = state::server(Config {
+            tagmsg_rate_limit: 3,
+            ..Config::default()
+        });
+        let (alice, alice_client) = state::register(&state, "alice");
+        let (bob, bob_client) = state::register(&state, "bob");
+        let (carol, carol_client) = state::register(&state, "carol");
+        let mut users = state.users.lock().unwrap();
+        let user = users.get_mut(&bob).unwrap();
+        user.capabilities.insert("message-tags".to_string());
+        drop(users);
+
+        for _ in 0..5 {
+            let message = Message::from("@+typing=active TAGMSG bob,carol").unwrap();
+            handle_message(message, &state, alice).unwrap();
+        }
+
+        let received = state::disconnect(&state, bob, bob_client);
+        assert_eq!(received.len(), 3);
+        assert!(received
+            .iter()
+            .all(|line| line.contains("+typing=active") && line.ends_with(" TAGMSG bob")));
+        assert!(state::disconnect(&state, carol, carol_client).is_empty());
+        assert!(state::disconnect(&state, alice, alice_client).is_empty());
     }
 
-    return None;
+    #[test]
+    fn departing_users_are_removed_even_after_a_panic() {
+        let state = state::server(Config::default());
+        let (alice, alice_client) = state::register(&state, "alice");
+        let (bob, bob_client) = state::register(&state, "bob");
+        let (carol, carol_client) = state::register(&state, "carol");
+        for user_id in [alice, bob, carol] {
+            handle_message(Message::from("JOIN #rust").unwrap(), &state, user_id).unwrap();
+        }
+
+        // Serving alice's connection failed
+        drop(Departure {
+            state: &state,
+            user_id: alice,
+            reason: "Connection reset by peer".to_string(),
+        });
+        // Handling one of bob's commands panicked while the users table was locked
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _departure = Departure {
+                state: &state,
+                user_id: bob,
+                reason: "Connection closed".to_string(),
+            };
+            let _lock = state.users.lock().unwrap();
+            panic!("Handler bug");
+        }));
+        assert!(result.is_err());
+
+        assert!(!state.users.is_poisoned());
+        let users = state.users.lock().unwrap();
+        assert!(!users.contains_key(&alice) && !users.contains_key(&bob));
+        drop(users);
+        let channel = state.channels.lock().unwrap()["#rust"].clone();
+        assert!(!channel.is_operator(alice));
+
+        state::disconnect(&state, alice, alice_client);
+        state::disconnect(&state, bob, bob_client);
+        let quits = state::disconnect(&state, carol, carol_client)
+            .into_iter()
+            .filter(|line| line.contains(" QUIT "))
+            .collect::<Vec<_>>();
+        assert!(quits[0].ends_with(":alice!alice@127.0.0.1 QUIT :Connection reset by peer"));
+        assert!(quits[1].ends_with(":bob!bob@127.0.0.1 QUIT :Internal error"));
+    }
+
+    #[test]
+    fn invalid_utf8_is_decoded_as_latin1() {
+        assert_eq!(
+            decode_line("PRIVMSG #rust :Grüße".as_bytes()),
+            "PRIVMSG #rust :Grüße"
+        );
+        assert_eq!(
+            decode_line(b"PRIVMSG #rust :Gr\xfc\xdfe"),
+            "PRIVMSG #rust :Grüße"
+        );
+    }
+
+    #[test]
+    fn peers_are_told_when_a_connection_is_dropped_without_quit() {
+        let state = state::server(Config::default());
+        let (alice, alice_client) = state::register(&state, "alice");
+        let (bob, bob_client) = state::register(&state, "bob");
+        for user_id in [alice, bob] {
+            handle_message(Message::from("JOIN #rust").unwrap(), &state, user_id).unwrap();
+        }
+        let mut stream = state.users.lock().unwrap()[&alice]
+            .stream
+            .try_clone()
+            .unwrap();
+
+        drop(alice_client);
+        serve_user(&mut stream, &state, alice, "127.0.0.1".parse().unwrap());
+
+        assert!(!state.users.lock().unwrap().contains_key(&alice));
+        let replies = state::disconnect(&state, bob, bob_client);
+        assert!(replies
+            .last()
+            .unwrap()
+            .ends_with(":alice!alice@127.0.0.1 QUIT :Connection reset by peer"));
+    }
 }
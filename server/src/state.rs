@@ -0,0 +1,292 @@
+use crate::{
+    accounts::AccountStore,
+    bans::BanList,
+    caps::{self, Capabilities},
+    channel_store::ChannelStore,
+    config::Config,
+    error::ServerError,
+    handler::Registry,
+    history::History,
+    server,
+    user::{Channel, User},
+};
+use std::{
+    collections::HashMap,
+    io,
+    net::IpAddr,
+    sync::{Arc, Condvar, Mutex, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+use uuid::Uuid;
+
+pub type UserTable = Mutex<HashMap<Uuid, User>>;
+pub type ChannelTable = Mutex<HashMap<String, Arc<Channel>>>;
+
+/// Everything shared between the connection threads.
+pub struct ServerState {
+    pub users: UserTable,
+    pub channels: ChannelTable,
+    pub config: RwLock<Config>,
+    /// File the configuration was loaded from, so it can be reloaded with REHASH
+    pub config_path: String,
+    pub bans: Mutex<BanList>,
+    pub accounts: Mutex<AccountStore>,
+    pub channel_store: Mutex<ChannelStore>,
+    pub history: Mutex<History>,
+    /// Capabilities offered with CAP, recomputed on REHASH
+    pub capabilities: RwLock<Capabilities>,
+    /// Handlers of the commands clients can send
+    pub handlers: Registry,
+    /// Open connections from each IP address
+    connections: Mutex<HashMap<IpAddr, usize>>,
+    /// What the server does once it's shut down, or `None` if nothing asked it to shut down yet
+    shutdown: Mutex<Option<ShutdownMode>>,
+    shutdown_requested: Condvar,
+}
+
+/// What the server does after shutting down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Exit, e.g. after DIE or SIGTERM
+    Exit,
+    /// Start again with the same arguments, after RESTART
+    Restart,
+}
+
+/// A connection counted against the connection limits until it's dropped.
+pub struct ConnectionSlot {
+    state: Arc<ServerState>,
+    address: IpAddr,
+}
+
+impl ServerState {
+    pub fn new(
+        config: Config,
+        config_path: &str,
+        bans: BanList,
+        accounts: AccountStore,
+        channel_store: ChannelStore,
+        history: History,
+    ) -> Self {
+        let capabilities = caps::supported(&config);
+
+        // Registered channels exist from the start, even with nobody in them
+        let channels = channel_store
+            .restore()
+            .into_iter()
+            .map(|channel| (channel.name.clone(), Arc::new(channel)))
+            .collect();
+
+        ServerState {
+            users: Mutex::new(HashMap::new()),
+            channels: Mutex::new(channels),
+            config: RwLock::new(config),
+            config_path: config_path.to_string(),
+            bans: Mutex::new(bans),
+            accounts: Mutex::new(accounts),
+            channel_store: Mutex::new(channel_store),
+            history: Mutex::new(history),
+            capabilities: RwLock::new(capabilities),
+            handlers: server::handlers(),
+            connections: Mutex::new(HashMap::new()),
+            shutdown: Mutex::new(None),
+            shutdown_requested: Condvar::new(),
+        }
+    }
+
+    /// Ask the main thread to shut the server down. Asking again while it's shutting down has no
+    /// effect.
+    pub fn request_shutdown(&self, mode: ShutdownMode) {
+        let mut shutdown = self.shutdown.lock().unwrap();
+        if shutdown.is_none() {
+            *shutdown = Some(mode);
+            self.shutdown_requested.notify_all();
+        }
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.lock().unwrap().is_some()
+    }
+
+    /// Block until something asks the server to shut down, and return what it should do after.
+    pub fn wait_for_shutdown(&self) -> ShutdownMode {
+        let shutdown = self
+            .shutdown_requested
+            .wait_while(self.shutdown.lock().unwrap(), |shutdown| shutdown.is_none())
+            .unwrap();
+        shutdown.unwrap()
+    }
+
+    /// Wait for every connection thread to end, giving up after `deadline`. Return whether they
+    /// all did.
+    pub fn wait_for_connections(&self, deadline: Duration) -> bool {
+        let start = Instant::now();
+        while !self.connections.lock().unwrap().is_empty() {
+            if start.elapsed() >= deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        true
+    }
+
+    /// Count a new connection from `address`, unless the server is full or the address already has
+    /// as many connections as its class allows. Return why the connection was refused if it was.
+    pub fn admit(self: &Arc<Self>, address: IpAddr) -> Result<ConnectionSlot, &'static str> {
+        let config = self.config.read().unwrap();
+        let class = config
+            .class_for(&address.to_string(), None)
+            .map(|class| class.name.as_str());
+        let max_per_ip = config.limits(class).max_clients_per_ip;
+
+        let mut connections = self.connections.lock().unwrap();
+        if connections.values().sum::<usize>() >= config.max_clients {
+            return Err("Server is full");
+        }
+        let count = connections.entry(address).or_default();
+        if *count >= max_per_ip {
+            return Err("Too many connections from your host");
+        }
+        *count += 1;
+
+        Ok(ConnectionSlot {
+            state: self.clone(),
+            address,
+        })
+    }
+
+    /// Save the settings of the registered channels after one of them changed. The users lock must
+    /// not be held when calling this.
+    pub fn save_channels(&self) -> io::Result<()> {
+        let channels = self
+            .channels
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        self.channel_store.lock().unwrap().save(&channels)
+    }
+
+    /// Drop channels that nobody is in anymore, unless they're registered. The users lock must not
+    /// be held when calling this.
+    pub fn remove_empty_channels(&self) -> Result<(), ServerError> {
+        let mut channels = self.channels.lock()?;
+        let users = self.users.lock()?;
+        channels.retain(|_, channel| {
+            channel.is_registered()
+                || users
+                    .values()
+                    .any(|user| user.channel.as_ref() == Some(channel))
+        });
+        Ok(())
+    }
+
+    /// Return the name the server uses as the prefix of its messages.
+    pub fn server_name(&self) -> String {
+        self.config.read().unwrap().server_name.clone()
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut connections = self.state.connections.lock().unwrap();
+        if let Some(count) = connections.get_mut(&self.address) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.address);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+    };
+
+    /// Return a server using `config`, with empty stores that aren't saved anywhere.
+    pub fn server(config: Config) -> ServerState {
+        let history = History::load(None, config.history_length).unwrap();
+        ServerState::new(
+            config,
+            "",
+            BanList::default(),
+            AccountStore::default(),
+            ChannelStore::default(),
+            history,
+        )
+    }
+
+    /// Add a user connected from localhost, returning their ID and the client's end of the
+    /// connection.
+    pub fn connect(state: &ServerState) -> (Uuid, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (client, _) = listener.accept().unwrap();
+        let user = User::new(stream.peer_addr().unwrap().ip(), stream).unwrap();
+        let user_id = user.id;
+        state.users.lock().unwrap().insert(user_id, user);
+        (user_id, client)
+    }
+
+    /// Add a user that registered as `nickname`, returning their ID and the client's end of the
+    /// connection.
+    pub fn register(state: &ServerState, nickname: &str) -> (Uuid, TcpStream) {
+        let (user_id, client) = connect(state);
+        let mut users = state.users.lock().unwrap();
+        let user = users.get_mut(&user_id).unwrap();
+        user.nickname = Some(nickname.to_string());
+        user.username = Some(nickname.to_string());
+        user.is_registered = true;
+        drop(users);
+        (user_id, client)
+    }
+
+    /// Remove a user and return every line they were sent. Dropping the user closes the connection
+    /// once the lines are written.
+    pub fn disconnect(state: &ServerState, user_id: Uuid, mut client: TcpStream) -> Vec<String> {
+        state.users.lock().unwrap().remove(&user_id);
+        let mut replies = String::new();
+        client.read_to_string(&mut replies).unwrap();
+        replies.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn admits_connections_within_their_class_limits() {
+        let config: Config = toml::from_str(
+            r#"
+            max_clients = 3
+            max_clients_per_ip = 2
+
+            [[classes]]
+            name = "local"
+            hosts = ["127.0.0.*"]
+            max_clients_per_ip = 1
+            "#,
+        )
+        .unwrap();
+        let state = Arc::new(server(config));
+        let admit = |address: &str| state.admit(address.parse().unwrap());
+
+        let local = admit("127.0.0.1").unwrap();
+        assert_eq!(
+            admit("127.0.0.1").err(),
+            Some("Too many connections from your host")
+        );
+        let _first = admit("10.0.0.1").unwrap();
+        let _second = admit("10.0.0.1").unwrap();
+        assert_eq!(admit("10.0.0.2").err(), Some("Server is full"));
+
+        drop(local);
+        assert_eq!(
+            admit("10.0.0.1").err(),
+            Some("Too many connections from your host")
+        );
+        let _third = admit("10.0.0.2").unwrap();
+    }
+}
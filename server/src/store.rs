@@ -0,0 +1,54 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    fs,
+    io::{self, ErrorKind},
+};
+use uuid::Uuid;
+
+/// Read a value saved as JSON at `path`. If the file doesn't exist yet, the default value is
+/// returned instead.
+pub fn load_json<T: DeserializeOwned + Default>(
+    path: &str,
+) -> Result<T, Box<dyn std::error::Error>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(serde_json::from_str(&contents)?),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Write a value as JSON to `path`, replacing whatever was saved there before. The value is
+/// written to a temporary file next to it first, then renamed over it, so a crash partway through
+/// leaves the old file intact rather than a truncated one.
+pub fn save_json<T: Serialize>(path: &str, value: &T) -> io::Result<()> {
+    // Saves of the same file can overlap, so each gets a temporary file of its own
+    let temporary_path = format!("{}.{}.tmp", path, Uuid::new_v4().to_simple());
+    let result = fs::write(&temporary_path, serde_json::to_string_pretty(value)?)
+        .and_then(|_| fs::rename(&temporary_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn saves_replace_the_file_without_leaving_temporary_files() {
+        let directory = std::env::temp_dir().join(format!("store-{}", Uuid::new_v4()));
+        fs::create_dir(&directory).unwrap();
+        let path = directory.join("values.json");
+        let path = path.to_str().unwrap();
+
+        save_json(path, &HashMap::from([("a", 1)])).unwrap();
+        save_json(path, &HashMap::from([("b", 2)])).unwrap();
+        let saved: HashMap<String, i32> = load_json(path).unwrap();
+        assert_eq!(saved, HashMap::from([("b".to_string(), 2)]));
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -1,40 +1,224 @@
+use crate::multiline::PendingMultiline;
+use serde::{Deserialize, Serialize};
+use shared::message::{Batch, Message, ToIrc};
 use std::{
-    net::{IpAddr, TcpStream},
-    sync::Arc,
+    collections::HashSet,
+    io::{self, Write},
+    net::{IpAddr, Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use uuid::Uuid;
 
+/// How long writing to a connection can block before it's given up on.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
 #[derive(Debug)]
 pub struct User {
     pub id: Uuid,
     pub nickname: Option<String>,
     pub username: Option<String>,
+    pub realname: Option<String>,
     pub hostname: String,
     pub channel: Option<Arc<Channel>>,
     pub is_registered: bool,
     pub is_away: bool,
+    pub modes: UserModes,
+    /// Channels the user has been invited to, each letting them join once past `+i`, `+k` and `+l`
+    pub invites: HashSet<String>,
+    /// Name of the operator block the user logged in with using OPER
+    pub operator: Option<String>,
+    /// Password sent with PASS before registering
+    pub password: Option<String>,
+    /// Address of the listener the user connected through
+    pub listener: String,
+    /// Name of the connection class the user was placed in, if any
+    pub class: Option<String>,
+    /// Capabilities enabled with CAP REQ
+    pub capabilities: HashSet<String>,
+    /// CAP version the client sent with CAP LS, or 0 if it didn't send one
+    pub cap_version: u32,
+    /// Whether registration is being held off until CAP END
+    pub cap_negotiating: bool,
+    /// Name of the account the user logged in to
+    pub account: Option<String>,
+    /// Data received so far in an AUTHENTICATE exchange, or `None` if there isn't one going on
+    pub sasl_buffer: Option<String>,
+    /// When the user will lose their nickname if it belongs to an account they haven't logged in to
+    pub nick_deadline: Option<Instant>,
+    /// Multi-line message the user is in the middle of sending
+    pub multiline: Option<PendingMultiline>,
+    /// When the user's current second of TAGMSGs started, and how many they've sent in it
+    pub tagmsg_window: (Instant, usize),
+    /// Nicknames the user asked to be told about when they come online or go offline
+    pub monitoring: Vec<String>,
+    /// Most bytes that can be waiting to be sent to the user, from their connection class
+    pub sendq: usize,
+    /// Whether the user stopped reading messages for long enough that their SendQ filled up
+    pub sendq_exceeded: bool,
+    /// Messages waiting to be written to the user's connection by its writer thread
+    outgoing: Sender<String>,
+    /// Bytes of the messages in `outgoing`
+    queued: Arc<AtomicUsize>,
     pub stream: TcpStream,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+/// Modes a user can have set with MODE. Operator status (`+o`) is tracked by `User::operator`
+/// instead.
+#[derive(Debug, Default)]
+pub struct UserModes {
+    /// `+i`: hidden from WHO and NAMES for users that don't share a channel with them
+    pub invisible: bool,
+    /// `+w`: receives WALLOPS
+    pub wallops: bool,
+    /// `+r`: logged in to a registered account, only ever set by the server
+    pub registered: bool,
+}
+
+#[derive(Debug)]
 pub struct Channel {
     pub id: Uuid,
     pub name: String,
+    pub modes: Mutex<ChannelModes>,
+    /// Members with channel operator status
+    pub operators: Mutex<HashSet<Uuid>>,
+    pub topic: Mutex<Option<Topic>>,
+    /// `+b`: masks of users who can't join
+    pub bans: Mutex<Vec<String>>,
+    /// Account that registered the channel, or `None` if it isn't registered
+    pub founder: Mutex<Option<String>>,
+    /// Accounts made channel operators when they join, besides the founder
+    pub access: Mutex<Vec<String>>,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelModes {
+    /// `+i`: users can only join when invited
+    pub invite_only: bool,
+    /// `+t`: only channel operators can change the topic
+    pub topic_lock: bool,
+    /// `+k`: users have to give this key to join
+    pub key: Option<String>,
+    /// `+l`: maximum number of members
+    pub limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Topic {
+    pub text: String,
+    /// Nickname of the user who set the topic
+    pub set_by: String,
+    /// Unix timestamp of when the topic was set
+    pub set_at: i64,
 }
 
 impl User {
-    pub fn new(hostname: IpAddr, writer: TcpStream) -> Self {
-        User {
+    /// Create a user for a connection, starting the thread that writes messages to it. Messages
+    /// are written in their own thread so that a client that's slow to read them doesn't hold up
+    /// everyone sending to it. The connection is closed once the user is dropped and every message
+    /// sent before then has been written.
+    pub fn new(hostname: IpAddr, writer: TcpStream) -> io::Result<Self> {
+        let (outgoing, receiver) = mpsc::channel::<String>();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let mut socket = writer.try_clone()?;
+        socket.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        let written = queued.clone();
+        thread::spawn(move || {
+            for text in receiver {
+                if socket.write_all(text.as_bytes()).is_err() {
+                    break;
+                }
+                written.fetch_sub(text.len(), Ordering::SeqCst);
+            }
+            let _ = socket.shutdown(Shutdown::Both);
+        });
+
+        Ok(User {
             id: Uuid::new_v4(),
             nickname: None,
             username: None,
+            realname: None,
             hostname: hostname.to_string(),
             channel: None,
             is_registered: false,
             is_away: false,
+            modes: UserModes::default(),
+            invites: HashSet::new(),
+            operator: None,
+            password: None,
+            listener: String::new(),
+            class: None,
+            capabilities: HashSet::new(),
+            cap_version: 0,
+            cap_negotiating: false,
+            account: None,
+            sasl_buffer: None,
+            nick_deadline: None,
+            multiline: None,
+            tagmsg_window: (Instant::now(), 0),
+            monitoring: vec![],
+            sendq: usize::MAX,
+            sendq_exceeded: false,
+            outgoing,
+            queued,
             stream: writer,
+        })
+    }
+
+    /// Send a message to the user, leaving out any tags their client hasn't negotiated. The `time`
+    /// and `account` tags only need `server-time` and `account-tag`, and `batch` needs `batch`,
+    /// while every other tag needs `message-tags`.
+    ///
+    /// The message is queued for the user's writer thread. If that would take the queue past the
+    /// user's SendQ, the connection is closed instead, and their connection thread disconnects them.
+    pub fn send<T: ToIrc>(&mut self, message: &T) -> io::Result<()> {
+        let message_tags = self.capabilities.contains("message-tags");
+        let server_time = self.capabilities.contains("server-time");
+        let account_tag = self.capabilities.contains("account-tag");
+        let batch = self.capabilities.contains("batch");
+        let text = message.to_irc_with_tags(&|key| match key {
+            "time" => message_tags || server_time,
+            "account" => message_tags || account_tag,
+            "batch" => batch,
+            _ => message_tags,
+        });
+
+        if self.sendq_exceeded {
+            return Ok(());
+        }
+        if self.queued.load(Ordering::SeqCst) + text.len() > self.sendq {
+            self.sendq_exceeded = true;
+            let _ = self.stream.shutdown(Shutdown::Both);
+            return Ok(());
         }
+        self.queued.fetch_add(text.len(), Ordering::SeqCst);
+        // The writer thread only stops early if the connection is already broken, which its
+        // connection thread finds out about on its own
+        let _ = self.outgoing.send(text);
+        Ok(())
+    }
+
+    /// Send messages to the user as a batch, or one after another if they haven't negotiated
+    /// `batch`.
+    pub fn send_batch(
+        &mut self,
+        prefix: &str,
+        batch: &Batch,
+        messages: Vec<Message>,
+    ) -> io::Result<()> {
+        let messages = if self.capabilities.contains("batch") {
+            batch.wrap(Some(prefix.to_string()), messages)
+        } else {
+            messages
+        };
+        messages.iter().try_for_each(|message| self.send(message))
     }
 
     pub fn prefix(&self) -> Option<String> {
@@ -44,6 +228,33 @@ impl User {
             None
         }
     }
+
+    /// Return the user's modes as a mode string, e.g. `+iw`.
+    pub fn mode_string(&self) -> String {
+        let mut modes = "+".to_string();
+        if self.modes.invisible {
+            modes.push('i');
+        }
+        if self.modes.wallops {
+            modes.push('w');
+        }
+        if self.operator.is_some() {
+            modes.push('o');
+        }
+        if self.modes.registered {
+            modes.push('r');
+        }
+        modes
+    }
+
+    /// Check whether `viewer` can see this user in WHO and NAMES replies. Invisible users can only
+    /// be seen by themselves, users in the same channel, and operators.
+    pub fn is_visible_to(&self, viewer: &User) -> bool {
+        !self.modes.invisible
+            || self.id == viewer.id
+            || (self.channel.is_some() && self.channel == viewer.channel)
+            || viewer.operator.is_some()
+    }
 }
 
 impl Channel {
@@ -51,6 +262,70 @@ impl Channel {
         Channel {
             id: Uuid::new_v4(),
             name: name.to_string(),
+            modes: Mutex::new(ChannelModes::default()),
+            operators: Mutex::new(HashSet::new()),
+            topic: Mutex::new(None),
+            bans: Mutex::new(vec![]),
+            founder: Mutex::new(None),
+            access: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn is_operator(&self, user_id: Uuid) -> bool {
+        self.operators.lock().unwrap().contains(&user_id)
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.founder.lock().unwrap().is_some()
+    }
+
+    /// Check whether an account is the founder or on the access list, which makes its users channel
+    /// operators when they join.
+    pub fn has_access(&self, account: &str) -> bool {
+        let is_founder = self
+            .founder
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|founder| founder.eq_ignore_ascii_case(account));
+        is_founder
+            || self
+                .access
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|entry| entry.eq_ignore_ascii_case(account))
+    }
+}
+
+impl PartialEq for Channel {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl ChannelModes {
+    /// Return the channel's modes as a mode string followed by their parameters, e.g. `+ikl`,
+    /// `secret` and `10`.
+    pub fn to_params(&self) -> Vec<String> {
+        let mut modes = "+".to_string();
+        let mut params = vec![];
+        if self.invite_only {
+            modes.push('i');
+        }
+        if self.topic_lock {
+            modes.push('t');
+        }
+        if let Some(key) = &self.key {
+            modes.push('k');
+            params.push(key.clone());
         }
+        if let Some(limit) = self.limit {
+            modes.push('l');
+            params.push(limit.to_string());
+        }
+
+        params.insert(0, modes);
+        params
     }
 }
@@ -0,0 +1,324 @@
+use crate::message::{Command, Message, ReplyCode, Response};
+use std::{
+    convert::TryFrom,
+    fmt::{Display, Formatter},
+};
+
+/// A command a client sends, with its parameters checked and named. Commands without a typed form
+/// yet are kept as `Other`, to be handled from their raw message.
+///
+/// Only the command and its parameters are part of it. The tags and prefix of the message it came
+/// from stay on the message.
+#[derive(Debug, Clone)]
+pub enum ClientCommand {
+    Pass {
+        password: String,
+    },
+    Nick {
+        nickname: String,
+    },
+    User {
+        username: String,
+        realname: String,
+    },
+    Join {
+        channels: Vec<String>,
+        /// Keys of the channels, in the same order
+        keys: Vec<String>,
+    },
+    Part {
+        channels: Vec<String>,
+        reason: Option<String>,
+    },
+    PrivMsg {
+        targets: Vec<String>,
+        text: String,
+    },
+    Notice {
+        targets: Vec<String>,
+        text: String,
+    },
+    TagMsg {
+        targets: Vec<String>,
+    },
+    Away {
+        /// Message to reply to anyone messaging the user, or `None` to no longer be away
+        text: Option<String>,
+    },
+    Quit {
+        reason: Option<String>,
+    },
+    Ping {
+        token: String,
+    },
+    Pong {
+        token: String,
+    },
+    Other(Message),
+}
+
+/// Why a message couldn't be turned into a `ClientCommand`, which the server tells the client with
+/// a numeric reply.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CommandError {
+    /// The command is missing a parameter it needs
+    NeedMoreParams(String),
+    /// NICK without a nickname
+    NoNicknameGiven,
+    /// PRIVMSG, NOTICE, or TAGMSG without a target
+    NoRecipient(String),
+    /// PRIVMSG or NOTICE without any text
+    NoTextToSend(String),
+}
+
+impl TryFrom<&Message> for ClientCommand {
+    type Error = CommandError;
+
+    fn try_from(message: &Message) -> Result<Self, Self::Error> {
+        let params = &message.params;
+        let name = message.command.to_string().to_uppercase();
+        let need = |count: usize| {
+            if params.len() < count {
+                Err(CommandError::NeedMoreParams(name.clone()))
+            } else {
+                Ok(())
+            }
+        };
+
+        let command = match message.command {
+            Command::Pass => {
+                need(1)?;
+                ClientCommand::Pass {
+                    password: params[0].clone(),
+                }
+            }
+            Command::Nick => ClientCommand::Nick {
+                nickname: params
+                    .first()
+                    .filter(|nickname| !nickname.is_empty())
+                    .ok_or(CommandError::NoNicknameGiven)?
+                    .clone(),
+            },
+            // The mode and unused parameters in between are ignored
+            Command::User => {
+                need(4)?;
+                ClientCommand::User {
+                    username: params[0].clone(),
+                    realname: params[3].clone(),
+                }
+            }
+            Command::Join => {
+                need(1)?;
+                ClientCommand::Join {
+                    channels: non_empty(split_list(&params[0]), &name)?,
+                    keys: params
+                        .get(1)
+                        .map(|keys| split_list(keys))
+                        .unwrap_or_default(),
+                }
+            }
+            Command::Part => {
+                need(1)?;
+                ClientCommand::Part {
+                    channels: non_empty(split_list(&params[0]), &name)?,
+                    reason: params.get(1).cloned(),
+                }
+            }
+            Command::PrivMsg | Command::Notice => {
+                let targets = params
+                    .first()
+                    .map(|targets| split_list(targets))
+                    .filter(|targets| !targets.is_empty())
+                    .ok_or_else(|| CommandError::NoRecipient(name.clone()))?;
+                let text = params
+                    .get(1)
+                    .filter(|text| !text.is_empty())
+                    .ok_or_else(|| CommandError::NoTextToSend(name.clone()))?
+                    .clone();
+                if matches!(message.command, Command::Notice) {
+                    ClientCommand::Notice { targets, text }
+                } else {
+                    ClientCommand::PrivMsg { targets, text }
+                }
+            }
+            Command::TagMsg => ClientCommand::TagMsg {
+                targets: params
+                    .first()
+                    .map(|targets| split_list(targets))
+                    .filter(|targets| !targets.is_empty())
+                    .ok_or(CommandError::NoRecipient(name))?,
+            },
+            Command::Away => ClientCommand::Away {
+                text: params.first().filter(|text| !text.is_empty()).cloned(),
+            },
+            Command::Quit => ClientCommand::Quit {
+                reason: params.first().cloned(),
+            },
+            Command::Ping => {
+                need(1)?;
+                ClientCommand::Ping {
+                    token: params[0].clone(),
+                }
+            }
+            Command::Pong => {
+                need(1)?;
+                ClientCommand::Pong {
+                    token: params[0].clone(),
+                }
+            }
+            _ => ClientCommand::Other(message.clone()),
+        };
+        Ok(command)
+    }
+}
+
+impl From<ClientCommand> for Message {
+    fn from(command: ClientCommand) -> Self {
+        let (command, params) = match command {
+            ClientCommand::Pass { password } => (Command::Pass, vec![password]),
+            ClientCommand::Nick { nickname } => (Command::Nick, vec![nickname]),
+            ClientCommand::User { username, realname } => (
+                Command::User,
+                vec![username, "0".to_string(), "*".to_string(), realname],
+            ),
+            ClientCommand::Join { channels, keys } => {
+                let mut params = vec![channels.join(",")];
+                if !keys.is_empty() {
+                    params.push(keys.join(","));
+                }
+                (Command::Join, params)
+            }
+            ClientCommand::Part { channels, reason } => {
+                let mut params = vec![channels.join(",")];
+                params.extend(reason);
+                (Command::Part, params)
+            }
+            ClientCommand::PrivMsg { targets, text } => {
+                (Command::PrivMsg, vec![targets.join(","), text])
+            }
+            ClientCommand::Notice { targets, text } => {
+                (Command::Notice, vec![targets.join(","), text])
+            }
+            ClientCommand::TagMsg { targets } => (Command::TagMsg, vec![targets.join(",")]),
+            ClientCommand::Away { text } => (Command::Away, text.into_iter().collect()),
+            ClientCommand::Quit { reason } => (Command::Quit, reason.into_iter().collect()),
+            ClientCommand::Ping { token } => (Command::Ping, vec![token]),
+            ClientCommand::Pong { token } => (Command::Pong, vec![token]),
+            ClientCommand::Other(message) => return message,
+        };
+        let params = params.iter().map(String::as_str).collect::<Vec<_>>();
+        Message::new(None, command, &params)
+    }
+}
+
+impl CommandError {
+    /// Return the numeric reply telling the client what was wrong with their command. NOTICEs
+    /// never get a reply, so that automatic replies can't loop.
+    pub fn reply(&self, server_prefix: &str) -> Option<Response> {
+        let response = match self {
+            CommandError::NoRecipient(command) | CommandError::NoTextToSend(command)
+                if command == "NOTICE" =>
+            {
+                return None
+            }
+            CommandError::NeedMoreParams(command) => Response::new(
+                server_prefix,
+                ReplyCode::ERR_NEEDMOREPARAMS,
+                &[command, "Not enough parameters."],
+            ),
+            CommandError::NoNicknameGiven => Response::new(
+                server_prefix,
+                ReplyCode::ERR_NONICKNAMEGIVEN,
+                &["No nickname was given."],
+            ),
+            CommandError::NoRecipient(_) => Response::new(
+                server_prefix,
+                ReplyCode::ERR_NORECIPIENT,
+                &["No recipient for the message was given."],
+            ),
+            CommandError::NoTextToSend(_) => Response::new(
+                server_prefix,
+                ReplyCode::ERR_NOTEXTTOSEND,
+                &["No text to send."],
+            ),
+        };
+        Some(response)
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NeedMoreParams(command) => {
+                write!(f, "{} is missing parameters", command)
+            }
+            CommandError::NoNicknameGiven => write!(f, "No nickname was given"),
+            CommandError::NoRecipient(command) => write!(f, "{} has no recipient", command),
+            CommandError::NoTextToSend(command) => write!(f, "{} has no text to send", command),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Split a comma-separated list parameter, e.g. `#rust,#irc`, leaving out empty items.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Check that a list parameter a command needs has at least one item in it.
+fn non_empty(list: Vec<String>, command: &str) -> Result<Vec<String>, CommandError> {
+    if list.is_empty() {
+        Err(CommandError::NeedMoreParams(command.to_string()))
+    } else {
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_to_and_from_messages() {
+        let message = Message::from("PRIVMSG #rust,alice :hello there").unwrap();
+        let command = ClientCommand::try_from(&message).unwrap();
+        match &command {
+            ClientCommand::PrivMsg { targets, text } => {
+                assert_eq!(targets, &["#rust", "alice"]);
+                assert_eq!(text, "hello there");
+            }
+            _ => panic!("Expected a PRIVMSG, got {:?}", command),
+        }
+        let message: Message = command.into();
+        assert_eq!(message.to_string(), "PRIVMSG #rust,alice :hello there");
+
+        let message = Message::from("JOIN #a,#b key").unwrap();
+        let message: Message = ClientCommand::try_from(&message).unwrap().into();
+        assert_eq!(message.to_string(), "JOIN #a,#b key");
+    }
+
+    #[test]
+    fn rejects_missing_parameters() {
+        let error = |raw: &str| ClientCommand::try_from(&Message::from(raw).unwrap()).unwrap_err();
+        assert_eq!(
+            error("USER guest 0"),
+            CommandError::NeedMoreParams("USER".to_string())
+        );
+        assert_eq!(error("NICK"), CommandError::NoNicknameGiven);
+        assert_eq!(
+            error("PRIVMSG #rust"),
+            CommandError::NoTextToSend("PRIVMSG".to_string())
+        );
+
+        let reply = error("JOIN").reply("irc.example.com").unwrap();
+        assert_eq!(
+            reply.to_string(),
+            ":irc.example.com 461 JOIN :Not enough parameters."
+        );
+        assert!(error("NOTICE").reply("irc.example.com").is_none());
+    }
+}
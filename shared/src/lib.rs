@@ -1,3 +1,8 @@
+pub mod command;
+pub mod message;
+// pub mod user;
+pub const MESSAGE_SIZE: usize = 1024;
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -6,7 +11,3 @@ mod tests {
         assert_eq!(result, 4);
     }
 }
-
-// pub mod message;
-// pub mod user;
-pub const MESSAGE_SIZE: usize = 1024;
@@ -0,0 +1,653 @@
+#![allow(non_camel_case_types)]
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fmt::{Display, Formatter},
+    io::{Error, ErrorKind},
+    str::FromStr,
+};
+use uuid::Uuid;
+
+/// Message tags mapped to their unescaped values. Tags without a value map to an empty string.
+pub type Tags = BTreeMap<String, String>;
+
+/// Most bytes of tags a client may send, not counting the leading `@` and trailing space.
+pub const MAX_CLIENT_TAGS_LENGTH: usize = 4094;
+/// Most bytes of tags in any message, including the ones added by the server.
+pub const MAX_TAGS_LENGTH: usize = 8191;
+/// Longest list sent in a single reply, e.g. of capabilities or nicknames, so the line stays under
+/// the IRC limit.
+pub const MAX_LIST_LENGTH: usize = 400;
+
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub tags: Tags,
+    pub prefix: Option<String>,
+    pub command: Command,
+    pub params: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub prefix: String,
+    pub code: ReplyCode,
+    pub params: Vec<String>,
+}
+
+/// A group of messages sent between `BATCH +<reference>` and `BATCH -<reference>`, such as history
+/// playback or the lines of a multi-line message. Each message in the batch carries a `batch` tag
+/// with its reference.
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub reference: String,
+    /// Type of the batch, e.g. `chathistory`
+    pub kind: String,
+    pub params: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Command {
+    Pass,
+    User,
+    Nick,
+    Join,
+    Kick,
+    Part,
+    PrivMsg,
+    List,
+    Away,
+    Quit,
+    Error,
+    Ping,
+    Pong,
+    Notice,
+    Mode,
+    Oper,
+    Kill,
+    Wallops,
+    Rehash,
+    Die,
+    Restart,
+    Kline,
+    Unkline,
+    Dline,
+    Undline,
+    Stats,
+    Names,
+    Who,
+    Invite,
+    Cap,
+    Authenticate,
+    Account,
+    Register,
+    Fail,
+    Topic,
+    Cregister,
+    Access,
+    ChatHistory,
+    Batch,
+    Redact,
+    TagMsg,
+    Monitor,
+    Unknown,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub enum ReplyCode {
+    RPL_WELCOME = 1,
+    RPL_YOURHOST = 2,
+    RPL_CREATED = 3,
+    RPL_MYINFO = 4,
+    RPL_ISUPPORT = 5,
+    RPL_STATSKLINE = 216,
+    RPL_ENDOFSTATS = 219,
+    RPL_STATSDLINE = 225,
+    RPL_UMODEIS = 221,
+    RPL_AWAY = 301,
+    RPL_UNAWAY = 305,
+    RPL_NOWAWAY = 306,
+    RPL_WHOISUSER = 311,
+    RPL_WHOISSERVER = 312,
+    RPL_WHOISOPERATOR = 313,
+    RPL_WHOISIDLE = 317,
+    RPL_ENDOFWHOIS = 318,
+    RPL_WHOISCHANNELS = 319,
+    RPL_WHOREPLY = 352,
+    RPL_ENDOFWHO = 315,
+    RPL_LIST = 322,
+    RPL_LISTEND = 323,
+    RPL_CHANNELMODEIS = 324,
+    RPL_NOTOPIC = 331,
+    RPL_TOPIC = 332,
+    RPL_TOPICWHOTIME = 333,
+    RPL_INVITING = 341,
+    RPL_NAMREPLY = 353,
+    RPL_ENDOFNAMES = 366,
+    RPL_BANLIST = 367,
+    RPL_ENDOFBANLIST = 368,
+    RPL_MOTDSTART = 375,
+    RPL_MOTD = 372,
+    RPL_ENDOFMOTD = 376,
+    RPL_YOUREOPER = 381,
+    RPL_REHASHING = 382,
+
+    ERR_NOSUCHNICK = 401,
+    ERR_NOSUCHSERVER = 402,
+    ERR_NOSUCHCHANNEL = 403,
+    ERR_CANNOTSENDTOCHAN = 404,
+    ERR_INVALIDCAPCMD = 410,
+    ERR_NORECIPIENT = 411,
+    ERR_NOTEXTTOSEND = 412,
+    ERR_INPUTTOOLONG = 417,
+    ERR_UNKNOWNCOMMAND = 421,
+    ERR_NOMOTD = 422,
+    ERR_NONICKNAMEGIVEN = 431,
+    ERR_NICKNAMEINUSE = 433,
+    ERR_USERNOTINCHANNEL = 441,
+    ERR_NOTONCHANNEL = 442,
+    ERR_USERONCHANNEL = 443,
+    ERR_NOTREGISTERED = 451,
+    ERR_NEEDMOREPARAMS = 461,
+    ERR_ALREADYREGISTRED = 462,
+    ERR_PASSWDMISMATCH = 464,
+    ERR_YOUREBANNEDCREEP = 465,
+    ERR_CHANNELISFULL = 471,
+    ERR_UNKNOWNMODE = 472,
+    ERR_INVITEONLYCHAN = 473,
+    ERR_BANNEDFROMCHAN = 474,
+    ERR_BADCHANNELKEY = 475,
+    ERR_NOPRIVILEGES = 481,
+    ERR_CHANOPRIVSNEEDED = 482,
+    ERR_NOOPERHOST = 491,
+    ERR_UMODEUNKNOWNFLAG = 501,
+    ERR_USERSDONTMATCH = 502,
+    RPL_LOGGEDIN = 900,
+    RPL_LOGGEDOUT = 901,
+    ERR_NICKLOCKED = 902,
+    RPL_SASLSUCCESS = 903,
+    ERR_SASLFAIL = 904,
+    ERR_SASLTOOLONG = 905,
+    ERR_SASLABORTED = 906,
+    ERR_SASLALREADY = 907,
+    RPL_SASLMECHS = 908,
+    RPL_MONONLINE = 730,
+    RPL_MONOFFLINE = 731,
+    RPL_MONLIST = 732,
+    RPL_ENDOFMONLIST = 733,
+    ERR_MONLISTFULL = 734,
+}
+
+pub trait ToIrc: ToString {
+    fn to_irc(&self) -> String {
+        format!("{}\r\n", self.to_string())
+    }
+
+    /// Same as `to_irc`, but only with the tags `keep` returns true for. This lets the server leave
+    /// out tags a client hasn't negotiated.
+    fn to_irc_with_tags(&self, _keep: &dyn Fn(&str) -> bool) -> String {
+        self.to_irc()
+    }
+}
+
+impl Message {
+    /// Parse an IRC message from a raw input string. Return a message if the input is formatted
+    /// properly. Otherwise, return an error describing the issue.
+    pub fn from(raw: &str) -> Result<Self, Error> {
+        // Trim line ending from input string
+        let mut raw = raw.trim_end();
+
+        // There are tags
+        let tags = if let Some(text) = raw.strip_prefix('@') {
+            let (tags, text) = Message::get_next_word(text);
+            if tags.len() > MAX_TAGS_LENGTH {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "Input string has too many tags.",
+                ));
+            }
+            raw = text;
+            parse_tags(tags)
+        } else {
+            Tags::new()
+        };
+
+        // There is a prefix
+        let prefix = if raw.starts_with(":") {
+            // Remove colon from the beginning of the string
+            let (_, text) = raw.split_once(":").unwrap();
+            // Cut first part of input out and store it in `prefix`
+            let (prefix, text) = Message::get_next_word(text);
+            // Set raw to input without prefix
+            raw = text;
+            // Return prefix
+            Some(prefix.to_string())
+        } else {
+            None
+        };
+
+        // Cut command word from string
+        let (command, text) = Message::get_next_word(raw);
+        if command.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "Input string does not contain a command.",
+            ));
+        }
+        // Convert command word to Command enum
+        // If the command isn't valid, it'll be parsed as Command::Unknown. This is so that the
+        // server can handle sending the response.
+        let Ok(command) = command.parse::<Command>();
+        // Set raw to input without command
+        raw = text;
+
+        let mut params = vec![];
+        // Add remaining parts of string to parameters
+        while !raw.is_empty() {
+            if raw.starts_with(":") {
+                let (_, param) = raw.split_once(":").unwrap();
+                params.push(param.to_string());
+                break;
+            } else {
+                let (param, text) = Message::get_next_word(raw);
+                params.push(param.to_string());
+                raw = text;
+                // println!("{raw:?}");
+            }
+        }
+
+        Ok(Message {
+            tags,
+            prefix,
+            command,
+            params,
+        })
+    }
+
+    pub fn new(prefix: Option<String>, command: Command, params: &[&str]) -> Self {
+        Message {
+            tags: Tags::new(),
+            prefix,
+            command,
+            params: params.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Return the length of the message's tags as they're sent, without the leading `@` and
+    /// trailing space.
+    pub fn tags_length(&self) -> usize {
+        format_tags(&self.tags, &|_| true).len()
+    }
+
+    /// Format the message, only including the tags `keep` returns true for.
+    fn format(&self, keep: &dyn Fn(&str) -> bool) -> String {
+        // Flatten list of arguments into a string with a colon for message
+        let arguments = format_params(&self.params);
+
+        let mut text = String::new();
+        let tags = format_tags(&self.tags, keep);
+        if !tags.is_empty() {
+            text.push_str(&format!("@{} ", tags));
+        }
+        if let Some(prefix) = &self.prefix {
+            text.push_str(&format!(":{} ", prefix));
+        }
+        text.push_str(&format!(
+            "{} {}",
+            self.command.to_string().to_uppercase(),
+            arguments
+        ));
+        text
+    }
+
+    /// Return the first subsequence of the string separated by a space as well as the rest of the
+    /// string. If the string has no spaces, return the input.
+    ///
+    /// This is an adjustment to `str::split_once` that returns the original input as well as an
+    /// empty string instead of `None`.
+    fn get_next_word(input: &str) -> (&str, &str) {
+        match input.split_once(" ") {
+            Some(pair) => pair,
+            None => (input, ""), // String is done
+        }
+    }
+}
+
+impl Response {
+    pub fn new(prefix: &str, code: ReplyCode, params: &[&str]) -> Self {
+        Response {
+            prefix: prefix.to_string(),
+            code,
+            params: params.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+}
+
+impl Batch {
+    /// Create a batch with a new, unique reference.
+    pub fn new(kind: &str, params: &[&str]) -> Self {
+        Batch {
+            reference: Uuid::new_v4().to_simple().to_string(),
+            kind: kind.to_string(),
+            params: params.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    /// Read the batch a `BATCH +<reference> <type> [params]` message starts.
+    pub fn from_start(message: &Message) -> Option<Self> {
+        let reference = message.params.first()?.strip_prefix('+')?;
+        Some(Batch {
+            reference: reference.to_string(),
+            kind: message.params.get(1)?.clone(),
+            params: message.params[2..].to_vec(),
+        })
+    }
+
+    /// Return the message that opens the batch.
+    pub fn start(&self, prefix: Option<String>) -> Message {
+        let mut params = vec![format!("+{}", self.reference), self.kind.clone()];
+        params.extend(self.params.iter().cloned());
+        Message {
+            tags: Tags::new(),
+            prefix,
+            command: Command::Batch,
+            params,
+        }
+    }
+
+    /// Return the message that closes the batch.
+    pub fn end(&self, prefix: Option<String>) -> Message {
+        Message::new(prefix, Command::Batch, &[&format!("-{}", self.reference)])
+    }
+
+    /// Mark a message as part of the batch. This also works on the start of another batch, which
+    /// nests it in this one.
+    pub fn tag(&self, message: &mut Message) {
+        message
+            .tags
+            .insert("batch".to_string(), self.reference.clone());
+    }
+
+    /// Return the messages tagged as part of the batch, between its start and end.
+    pub fn wrap(&self, prefix: Option<String>, messages: Vec<Message>) -> Vec<Message> {
+        let mut batch = vec![self.start(prefix.clone())];
+        batch.extend(messages.into_iter().map(|mut message| {
+            self.tag(&mut message);
+            message
+        }));
+        batch.push(self.end(prefix));
+        batch
+    }
+}
+
+impl FromStr for Command {
+    type Err = Infallible;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        Ok(match input.to_uppercase().as_str() {
+            "PASS" => Command::Pass,
+            "USER" => Command::User,
+            "NICK" => Command::Nick,
+            "JOIN" => Command::Join,
+            "KICK" => Command::Kick,
+            "PART" => Command::Part,
+            "PRIVMSG" => Command::PrivMsg,
+            "LIST" => Command::List,
+            "AWAY" => Command::Away,
+            "QUIT" => Command::Quit,
+            "PING" => Command::Ping,
+            "PONG" => Command::Pong,
+            "ERROR" => Command::Error,
+            "NOTICE" => Command::Notice,
+            "MODE" => Command::Mode,
+            "OPER" => Command::Oper,
+            "KILL" => Command::Kill,
+            "WALLOPS" => Command::Wallops,
+            "REHASH" => Command::Rehash,
+            "DIE" => Command::Die,
+            "RESTART" => Command::Restart,
+            "KLINE" => Command::Kline,
+            "UNKLINE" => Command::Unkline,
+            "DLINE" => Command::Dline,
+            "UNDLINE" => Command::Undline,
+            "STATS" => Command::Stats,
+            "NAMES" => Command::Names,
+            "WHO" => Command::Who,
+            "INVITE" => Command::Invite,
+            "CAP" => Command::Cap,
+            "AUTHENTICATE" => Command::Authenticate,
+            "ACCOUNT" => Command::Account,
+            "REGISTER" => Command::Register,
+            "FAIL" => Command::Fail,
+            "TOPIC" => Command::Topic,
+            "CREGISTER" => Command::Cregister,
+            "ACCESS" => Command::Access,
+            "CHATHISTORY" => Command::ChatHistory,
+            "BATCH" => Command::Batch,
+            "REDACT" => Command::Redact,
+            "TAGMSG" => Command::TagMsg,
+            "MONITOR" => Command::Monitor,
+            _ => Command::Unknown,
+        })
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.format(&|_| true))
+    }
+}
+
+impl ToIrc for Message {
+    fn to_irc_with_tags(&self, keep: &dyn Fn(&str) -> bool) -> String {
+        format!("{}\r\n", self.format(keep))
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl Display for Response {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        // Flatten list of arguments into a string with a colon for message
+        let arguments = format_params(&self.params);
+
+        write!(f, ":{} {:03} {}", self.prefix, self.code as u16, arguments)
+    }
+}
+
+impl ToIrc for Response {}
+
+/// Join parameters into a string. The last one is prefixed with a colon when it needs to be, which
+/// is when it contains spaces, starts with a colon, or is empty.
+fn format_params(params: &[String]) -> String {
+    params
+        .iter()
+        .enumerate()
+        .map(|(i, x)| {
+            if i + 1 == params.len() && (x.is_empty() || x.contains(' ') || x.starts_with(':')) {
+                format!(":{}", x)
+            } else {
+                x.to_string()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parse the tags of a message, e.g. `time=2023-01-01T00:00:00.000Z;+draft/react=\:)`, without the
+/// leading `@`.
+fn parse_tags(input: &str) -> Tags {
+    input
+        .split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once('=') {
+            Some((key, value)) => (key.to_string(), unescape_tag_value(value)),
+            None => (tag.to_string(), String::new()),
+        })
+        .collect()
+}
+
+/// Join tags into a string, leaving out the ones `keep` returns false for.
+fn format_tags(tags: &Tags, keep: &dyn Fn(&str) -> bool) -> String {
+    tags.iter()
+        .filter(|(key, _)| keep(key))
+        .map(|(key, value)| {
+            if value.is_empty() {
+                key.clone()
+            } else {
+                format!("{}={}", key, escape_tag_value(value))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Escape the characters that can't appear in a tag value as is.
+pub fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undo `escape_tag_value`. An invalid escape becomes the character after the backslash, and a
+/// trailing backslash is dropped.
+pub fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(c) => unescaped.push(c),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// Join a list into as few lines as possible for replies that list things, none longer than
+/// `MAX_LIST_LENGTH` unless a single item is. An empty list gives no lines.
+pub fn join_list(items: &[String], separator: char) -> Vec<String> {
+    let mut lines: Vec<String> = vec![];
+    for item in items {
+        match lines.last_mut() {
+            Some(line) if line.len() + 1 + item.len() <= MAX_LIST_LENGTH => {
+                line.push(separator);
+                line.push_str(item);
+            }
+            _ => lines.push(item.clone()),
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_tags() {
+        let message =
+            Message::from("@+draft/react=\\:)\\s;msgid=abc;+flag :alice PRIVMSG #rust :hi")
+                .unwrap();
+        assert_eq!(message.tags["+draft/react"], ";) ");
+        assert_eq!(message.tags["msgid"], "abc");
+        assert_eq!(message.tags["+flag"], "");
+        assert_eq!(message.prefix.as_deref(), Some("alice"));
+        assert_eq!(message.params, ["#rust", "hi"]);
+    }
+
+    #[test]
+    fn escapes_tag_values() {
+        let value = "a;b c\\d\r\n";
+        assert_eq!(escape_tag_value(value), "a\\:b\\sc\\\\d\\r\\n");
+        assert_eq!(unescape_tag_value(&escape_tag_value(value)), value);
+        assert_eq!(unescape_tag_value("a\\bc\\"), "abc");
+    }
+
+    #[test]
+    fn wraps_messages_in_batches() {
+        let batch = Batch::new("chathistory", &["#rust"]);
+        let message = Message::new(None, Command::PrivMsg, &["#rust", "hi"]);
+        let lines = batch
+            .wrap(Some("irc.example.com".to_string()), vec![message])
+            .iter()
+            .map(Message::to_string)
+            .collect::<Vec<_>>();
+        let reference = &batch.reference;
+        assert_eq!(
+            lines,
+            [
+                format!(":irc.example.com BATCH +{reference} chathistory #rust"),
+                format!("@batch={reference} PRIVMSG #rust hi"),
+                format!(":irc.example.com BATCH -{reference}"),
+            ]
+        );
+
+        let parsed = Batch::from_start(&Message::from(&lines[0]).unwrap()).unwrap();
+        assert_eq!(&parsed.reference, reference);
+        assert_eq!(parsed.kind, "chathistory");
+        assert_eq!(parsed.params, ["#rust"]);
+    }
+
+    #[test]
+    fn formats_only_kept_tags() {
+        let mut message = Message::new(None, Command::PrivMsg, &["#rust", "hello there"]);
+        message
+            .tags
+            .insert("+typing".to_string(), "active".to_string());
+        message.tags.insert("msgid".to_string(), "a b".to_string());
+        assert_eq!(
+            message.to_string(),
+            "@+typing=active;msgid=a\\sb PRIVMSG #rust :hello there"
+        );
+        assert_eq!(
+            message.to_irc_with_tags(&|key| key.starts_with('+')),
+            "@+typing=active PRIVMSG #rust :hello there\r\n"
+        );
+        assert_eq!(
+            message.to_irc_with_tags(&|_| false),
+            "PRIVMSG #rust :hello there\r\n"
+        );
+    }
+
+    #[test]
+    fn joins_lists_within_the_length_limit() {
+        let items = [
+            "a".repeat(250),
+            "b".repeat(149),
+            "c".repeat(500),
+            "d".to_string(),
+        ];
+        let lines = join_list(&items, ',');
+        assert_eq!(
+            lines,
+            [
+                format!("{},{}", items[0], items[1]),
+                items[2].clone(),
+                "d".to_string()
+            ]
+        );
+        assert!(join_list(&[], ' ').is_empty());
+    }
+}